@@ -1,15 +1,14 @@
-use std::ptr::NonNull;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
-use std::sync::atomic::{AtomicPtr, AtomicUsize};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use hyaline_smr as hyaline;
+use hyaline_smr::{self as hyaline, Atomic, Owned};
 
 use rand::Rng;
 
-fn worker(a: Arc<AtomicPtr<AtomicUsize>>) -> usize {
+fn worker(a: Arc<Atomic<AtomicUsize>>) -> usize {
     let mut rng = rand::thread_rng();
     let mut sum = 0;
 
@@ -24,10 +23,10 @@ fn worker(a: Arc<AtomicPtr<AtomicUsize>>) -> usize {
             let guard = hyaline::pin();
 
             let val = if rng.gen() {
-                let t = Box::new(AtomicUsize::new(sum));
-                let p = a.swap(Box::into_raw(t), AcqRel);
+                let t = Owned::new(AtomicUsize::new(sum));
+                let p = a.swap(t, AcqRel, &guard);
                 unsafe {
-                    hyaline::retire(NonNull::new(p), &guard);
+                    guard.retire(p);
                     if let Some(act_p) = p.as_ref() {
                         act_p.load(Relaxed)
                     } else {
@@ -35,7 +34,7 @@ fn worker(a: Arc<AtomicPtr<AtomicUsize>>) -> usize {
                     }
                 }
             } else {
-                let p = a.load(Acquire);
+                let p = a.load(Acquire, &guard);
                 unsafe {
                     if let Some(act_p) = p.as_ref() {
                         act_p.fetch_add(sum, Relaxed)
@@ -53,8 +52,7 @@ fn worker(a: Arc<AtomicPtr<AtomicUsize>>) -> usize {
 
 fn main() {
     for _ in 0..100 {
-        let temp = Box::new(AtomicUsize::new(777));
-        let a = Arc::new(AtomicPtr::new(Box::into_raw(temp)));
+        let a = Arc::new(Atomic::new(AtomicUsize::new(777)));
 
         let threads = (0..16)
             .map(|_| {
@@ -66,8 +64,8 @@ fn main() {
         for t in threads {
             t.join().unwrap();
         }
-        let new_temp = Box::new(AtomicUsize::new(777));
-
-        a.swap(Box::into_raw(new_temp), AcqRel);
+        let guard = hyaline::pin();
+        let old = a.swap(Owned::new(AtomicUsize::new(777)), AcqRel, &guard);
+        unsafe { guard.retire(old) };
     }
 }
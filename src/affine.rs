@@ -0,0 +1,104 @@
+//! Deferred functions that have to run on the thread that deferred them.
+//!
+//! The node handed to the collector does not run the function itself. Once it is safe to
+//! run, whichever thread reclaims the node pushes the function back into the queue of its
+//! original thread, which runs it the next time it unpins or flushes.
+
+use std::cell::OnceCell;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::deferred::Deferred;
+use crate::primitive::thread_local;
+
+thread_local! {
+    static LOCAL_QUEUE: QueueOwner = const { QueueOwner(OnceCell::new()) };
+}
+
+/// A function which is only ever called on the thread that created it.
+#[derive(Debug)]
+struct ThreadBound(Deferred);
+
+// The function is only moved through other threads, never called there.
+unsafe impl Send for ThreadBound {}
+
+#[derive(Debug)]
+struct LocalQueue {
+    /// `None` once the owning thread exited.
+    pending: Mutex<Option<Vec<ThreadBound>>>,
+    has_pending: AtomicBool,
+}
+
+impl LocalQueue {
+    fn push(&self, f: ThreadBound) {
+        let mut pending = self.pending.lock().unwrap();
+        // The functions arriving after the thread exited can not run anywhere and are leaked.
+        if let Some(pending) = pending.as_mut() {
+            pending.push(f);
+            self.has_pending.store(true, Ordering::Release);
+        }
+    }
+
+    fn drain(&self) {
+        if !self.has_pending.load(Ordering::Acquire) {
+            return;
+        }
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            self.has_pending.store(false, Ordering::Relaxed);
+            pending.as_mut().map(mem::take)
+        };
+        // Run outside of the lock, the functions may defer more work.
+        for f in pending.into_iter().flatten() {
+            f.0.call();
+        }
+    }
+
+    fn close(&self) -> Vec<ThreadBound> {
+        self.pending.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+struct QueueOwner(OnceCell<Arc<LocalQueue>>);
+
+impl Drop for QueueOwner {
+    fn drop(&mut self) {
+        if let Some(queue) = self.0.get() {
+            for f in queue.close() {
+                f.0.call();
+            }
+        }
+    }
+}
+
+/// Wraps `f` into a function that can be deferred to any thread and sends `f` back to the
+/// current thread when it is called. Returns `None` if the current thread is exiting, in
+/// which case `f` is leaked.
+pub(crate) fn bind_to_thread<F: FnOnce() + 'static>(f: F) -> Option<impl FnOnce() + Send> {
+    // A `Deferred` which is dropped without being called leaks `f`.
+    let f = ThreadBound(Deferred::new(f));
+    let queue = LOCAL_QUEUE
+        .try_with(|owner| {
+            owner
+                .0
+                .get_or_init(|| {
+                    Arc::new(LocalQueue {
+                        pending: Mutex::new(Some(Vec::new())),
+                        has_pending: AtomicBool::new(false),
+                    })
+                })
+                .clone()
+        })
+        .ok()?;
+    Some(move || queue.push(f))
+}
+
+/// Runs the functions of the current thread that became safe to run.
+pub(crate) fn drain() {
+    let _ = LOCAL_QUEUE.try_with(|owner| {
+        if let Some(queue) = owner.0.get() {
+            queue.drain();
+        }
+    });
+}
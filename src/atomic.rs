@@ -0,0 +1,489 @@
+//! Typed pointers whose lifetime is bound to a [`Guard`].
+//!
+//! [`Atomic`] is a shared pointer to a heap allocated value. Loading it yields a [`Shared`]
+//! which borrows the guard it was loaded with, so the borrow checker makes sure it is not
+//! used after the thread got unpinned. [`Owned`] is a value that has not been shared yet.
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::collector::Smr;
+use crate::guard::Guard;
+
+/// A pointer that can be stored into an [`Atomic`], either an [`Owned`] or a [`Shared`].
+pub trait Pointer<T> {
+    /// Returns the raw pointer, giving up the ownership if there is any.
+    fn into_ptr(self) -> *mut T;
+
+    /// Recreates the pointer from a raw pointer returned by [`Pointer::into_ptr`].
+    ///
+    /// # Safety
+    /// `ptr` must come from `into_ptr` of the same type and must not have been recreated before.
+    unsafe fn from_ptr(ptr: *mut T) -> Self;
+}
+
+/// An atomic pointer that can be shared between threads.
+///
+/// Dropping an `Atomic` does not drop the value it points to.
+pub struct Atomic<T> {
+    data: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for Atomic<T> {}
+unsafe impl<T: Send + Sync> Sync for Atomic<T> {}
+
+impl<T> Atomic<T> {
+    /// Allocates `value` on the heap and returns a new atomic pointer to it.
+    pub fn new(value: T) -> Atomic<T> {
+        Atomic::from(Owned::new(value))
+    }
+
+    /// Returns a new null atomic pointer.
+    pub const fn null() -> Atomic<T> {
+        Atomic {
+            data: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Loads the pointer on behalf of the thread pinned by `guard`, see [`Guard::protect`].
+    pub fn load<'g, C: Smr + ?Sized>(
+        &self,
+        order: Ordering,
+        guard: &'g Guard<'_, C>,
+    ) -> Shared<'g, T> {
+        unsafe { Shared::from_ptr(guard.protect(&self.data, order)) }
+    }
+
+    /// Stores `new` into the atomic pointer.
+    pub fn store<P: Pointer<T>>(&self, new: P, order: Ordering) {
+        self.data.store(new.into_ptr(), order);
+    }
+
+    /// Stores `new` into the atomic pointer and returns the previous value.
+    pub fn swap<'g, P: Pointer<T>, C: Smr + ?Sized>(
+        &self,
+        new: P,
+        order: Ordering,
+        guard: &'g Guard<'_, C>,
+    ) -> Shared<'g, T> {
+        let old = self.data.swap(new.into_ptr(), order);
+        // The swapped out value may be retired right after, the guard has to cover it.
+        guard.protect_observed(&self.data, old, Ordering::Relaxed);
+        unsafe { Shared::from_ptr(old) }
+    }
+
+    /// Stores `new` if the pointer is still `current`.
+    ///
+    /// On success the stored pointer is returned. On failure the value the pointer was found
+    /// to hold is protected by `guard` and returned along with `new`, so that an [`Owned`] is
+    /// not lost. Collectors which track what a thread may reach, like
+    /// [`RobustCollector`](crate::RobustCollector), may return a newer value loaded with the
+    /// `failure` ordering instead.
+    pub fn compare_exchange<'g, P: Pointer<T>, C: Smr + ?Sized>(
+        &self,
+        current: Shared<'_, T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        guard: &'g Guard<'_, C>,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        let new = new.into_ptr();
+        match self
+            .data
+            .compare_exchange(current.as_raw() as *mut T, new, success, failure)
+        {
+            Ok(old) => {
+                guard.protect_observed(&self.data, old, Ordering::Relaxed);
+                Ok(unsafe { Shared::from_ptr(new) })
+            }
+            Err(observed) => Err(CompareExchangeError {
+                current: unsafe {
+                    Shared::from_ptr(guard.protect_observed(&self.data, observed, failure))
+                },
+                new: unsafe { P::from_ptr(new) },
+            }),
+        }
+    }
+
+    /// Same as [`Atomic::compare_exchange`] but is allowed to fail spuriously.
+    pub fn compare_exchange_weak<'g, P: Pointer<T>, C: Smr + ?Sized>(
+        &self,
+        current: Shared<'_, T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        guard: &'g Guard<'_, C>,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        let new = new.into_ptr();
+        match self
+            .data
+            .compare_exchange_weak(current.as_raw() as *mut T, new, success, failure)
+        {
+            Ok(old) => {
+                guard.protect_observed(&self.data, old, Ordering::Relaxed);
+                Ok(unsafe { Shared::from_ptr(new) })
+            }
+            Err(observed) => Err(CompareExchangeError {
+                current: unsafe {
+                    Shared::from_ptr(guard.protect_observed(&self.data, observed, failure))
+                },
+                new: unsafe { P::from_ptr(new) },
+            }),
+        }
+    }
+
+    /// Takes ownership of the pointed value.
+    ///
+    /// # Safety
+    /// The pointer must not be null and no other thread may be using the value anymore,
+    /// for example because the data structure holding it is being dropped.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_ptr(self.data.into_inner())
+    }
+}
+
+impl<T> From<Owned<T>> for Atomic<T> {
+    fn from(owned: Owned<T>) -> Self {
+        Atomic {
+            data: AtomicPtr::new(owned.into_ptr()),
+        }
+    }
+}
+
+impl<T> Default for Atomic<T> {
+    fn default() -> Self {
+        Atomic::null()
+    }
+}
+
+impl<T> fmt::Debug for Atomic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Atomic")
+            .field(&self.data.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// The error returned by a failed compare and exchange of an [`Atomic`].
+#[derive(Debug)]
+pub struct CompareExchangeError<'g, T, P: Pointer<T>> {
+    /// The value the pointer was found to hold, protected by the guard.
+    pub current: Shared<'g, T>,
+    /// The value that was to be stored, given back to the caller.
+    pub new: P,
+}
+
+/// A heap allocated value that is not shared with other threads yet.
+#[derive(Debug)]
+pub struct Owned<T> {
+    data: Box<T>,
+}
+
+impl<T> Owned<T> {
+    /// Allocates `value` on the heap.
+    pub fn new(value: T) -> Owned<T> {
+        Owned {
+            data: Box::new(value),
+        }
+    }
+
+    /// Returns the box holding the value.
+    pub fn into_box(self) -> Box<T> {
+        self.data
+    }
+
+    /// Converts the value into a [`Shared`] for the thread pinned by `guard`.
+    pub fn into_shared<'g, C: Smr + ?Sized>(self, _guard: &'g Guard<'_, C>) -> Shared<'g, T> {
+        unsafe { Shared::from_ptr(self.into_ptr()) }
+    }
+}
+
+impl<T> Pointer<T> for Owned<T> {
+    fn into_ptr(self) -> *mut T {
+        Box::into_raw(self.data)
+    }
+
+    unsafe fn from_ptr(ptr: *mut T) -> Self {
+        Owned {
+            data: Box::from_raw(ptr),
+        }
+    }
+}
+
+impl<T> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for Owned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+impl<T> From<T> for Owned<T> {
+    fn from(value: T) -> Self {
+        Owned::new(value)
+    }
+}
+
+impl<T> From<Box<T>> for Owned<T> {
+    fn from(data: Box<T>) -> Self {
+        Owned { data }
+    }
+}
+
+/// A pointer loaded from an [`Atomic`], valid for as long as the guard `'g` is alive.
+pub struct Shared<'g, T> {
+    data: *mut T,
+    _marker: PhantomData<(&'g (), *const T)>,
+}
+
+impl<'g, T> Shared<'g, T> {
+    /// Returns a null pointer.
+    pub fn null() -> Shared<'g, T> {
+        Shared {
+            data: ptr::null_mut(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns true if the pointer is null.
+    pub fn is_null(&self) -> bool {
+        self.data.is_null()
+    }
+
+    /// Returns the raw pointer.
+    pub fn as_raw(&self) -> *const T {
+        self.data
+    }
+
+    /// Returns the raw pointer as expected by [`Smr::retire`].
+    pub fn as_non_null(&self) -> Option<NonNull<T>> {
+        NonNull::new(self.data)
+    }
+
+    /// Dereferences the pointer.
+    ///
+    /// # Safety
+    /// The pointer must not be null and must point to a value that was not retired before
+    /// the guard `'g` was pinned.
+    pub unsafe fn deref(&self) -> &'g T {
+        &*self.data
+    }
+
+    /// Dereferences the pointer, returning `None` if it is null.
+    ///
+    /// # Safety
+    /// Same as [`Shared::deref`] apart from the pointer being allowed to be null.
+    pub unsafe fn as_ref(&self) -> Option<&'g T> {
+        self.data.as_ref()
+    }
+
+    /// Takes ownership of the pointed value.
+    ///
+    /// # Safety
+    /// The pointer must not be null and no other thread may be using the value anymore.
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        Owned::from_ptr(self.data)
+    }
+}
+
+impl<T> Pointer<T> for Shared<'_, T> {
+    fn into_ptr(self) -> *mut T {
+        self.data
+    }
+
+    unsafe fn from_ptr(ptr: *mut T) -> Self {
+        Shared {
+            data: ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Shared<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Shared<'_, T> {}
+
+impl<T> PartialEq for Shared<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl<T> Eq for Shared<'_, T> {}
+
+impl<T> fmt::Debug for Shared<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Shared").field(&self.data).finish()
+    }
+}
+
+#[cfg(all(test, not(loom), feature = "double-width"))]
+mod tests {
+    use std::{mem::ManuallyDrop, ptr, sync::atomic::Ordering, thread};
+
+    use crate::test_util::DropCounter;
+    use crate::{Atomic, Collector, Owned, RobustCollector, Shared, Smr};
+
+    const MAX_THREADS: usize = 8;
+
+    struct Node<T> {
+        data: ManuallyDrop<T>,
+        next: Atomic<Node<T>>,
+    }
+
+    struct TreiberStack<T> {
+        head: Atomic<Node<T>>,
+        collector: Collector,
+    }
+
+    impl<T: Send + Sync + 'static> TreiberStack<T> {
+        fn push(&self, t: T) {
+            let mut n = Owned::new(Node {
+                data: ManuallyDrop::new(t),
+                next: Atomic::null(),
+            });
+            let guard = self.collector.pin();
+            loop {
+                let head = self.head.load(Ordering::Relaxed, &guard);
+                n.next.store(head, Ordering::Relaxed);
+                match self.head.compare_exchange(
+                    head,
+                    n,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                    &guard,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => n = e.new,
+                }
+            }
+        }
+
+        fn pop(&self) -> Option<T> {
+            let guard = self.collector.pin();
+            loop {
+                let head = self.head.load(Ordering::Acquire, &guard);
+                let h = unsafe { head.as_ref() }?;
+                let next = h.next.load(Ordering::Relaxed, &guard);
+                if self
+                    .head
+                    .compare_exchange(head, next, Ordering::Relaxed, Ordering::Relaxed, &guard)
+                    .is_ok()
+                {
+                    unsafe {
+                        guard.retire(head);
+                        return Some(ManuallyDrop::into_inner(ptr::read(&h.data)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn treiber_stack() {
+        let drops = DropCounter::new();
+
+        let stack = TreiberStack {
+            head: Atomic::null(),
+            collector: Collector::new(),
+        };
+        thread::scope(|s| {
+            for _i in 0..MAX_THREADS {
+                s.spawn(|| {
+                    for _j in 0..1000 {
+                        stack.push(drops.counted());
+                        assert!(stack.pop().is_some());
+                    }
+                });
+            }
+        });
+        assert!(stack.pop().is_none());
+        assert_eq!(drops.count(), MAX_THREADS * 1000);
+    }
+
+    #[test]
+    fn compare_exchange_returns_owned() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let atomic = Atomic::new(1);
+        let res = atomic.compare_exchange(
+            Shared::null(),
+            Owned::new(2),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            &guard,
+        );
+        let err = res.unwrap_err();
+        assert_eq!(*err.new, 2);
+        assert_eq!(unsafe { *err.current.deref() }, 1);
+        drop(unsafe { atomic.into_owned() });
+    }
+
+    #[test]
+    fn swapped_out_pointer_is_protected() {
+        let drops = DropCounter::new();
+
+        let collector = RobustCollector::new();
+        let atomic = Atomic::null();
+        let guard = collector.pin();
+        // The value is born long after the guard last read anything.
+        for _i in 0..1000 {
+            collector.birth_era();
+        }
+        let era = collector.birth_era();
+        atomic.store(Owned::new(drops.counted()), Ordering::Release);
+        let old = atomic.swap(Shared::null(), Ordering::AcqRel, &guard);
+        unsafe { collector.retire_born(old.as_non_null(), era, &guard) };
+        guard.flush();
+        assert_eq!(drops.count(), 0);
+        drop(guard);
+        collector.flush();
+        assert_eq!(drops.count(), 1);
+    }
+
+    #[test]
+    fn failed_compare_exchange_protects_observed() {
+        let drops = DropCounter::new();
+
+        let collector = RobustCollector::new();
+        let atomic = Atomic::null();
+        let guard = collector.pin();
+        // The value is born long after the guard last read anything.
+        for _i in 0..1000 {
+            collector.birth_era();
+        }
+        let era = collector.birth_era();
+        atomic.store(Owned::new(drops.counted()), Ordering::Release);
+        let err = atomic
+            .compare_exchange(
+                Shared::null(),
+                Shared::null(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                &guard,
+            )
+            .unwrap_err();
+        assert!(!err.current.is_null());
+        atomic.store(Shared::null(), Ordering::Release);
+        unsafe { collector.retire_born(err.current.as_non_null(), era, &guard) };
+        guard.flush();
+        assert_eq!(drops.count(), 0);
+        drop(guard);
+        collector.flush();
+        assert_eq!(drops.count(), 1);
+    }
+}
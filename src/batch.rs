@@ -1,66 +1,315 @@
-use std::cell::RefCell;
-use std::{marker::PhantomData, ptr::NonNull};
-
-use crate::collector::Collector;
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+#[cfg(not(feature = "std"))]
+use core::cell::UnsafeCell;
+use core::{marker::PhantomData, ptr::NonNull};
+
+#[cfg(feature = "std")]
+use crate::affine;
 use crate::node::Node;
+use crate::shared::SharedState;
+#[cfg(feature = "stats")]
+use crate::stats::PendingCount;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::AtomicBool;
 
 use crate::primitive::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
 use crate::primitive::thread_local;
 
+#[cfg(feature = "std")]
 thread_local! {
-    static LOCAL_BATCH:RefCell<BatchHandle> = RefCell::new(BatchHandle::new());
+    // One batch per collector the thread has retired into.
+    static LOCAL_BATCHES: RefCell<Vec<LocalBatch>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) const BATCH_SIZE: usize = 64;
+
+/// Implemented by the shared state of the collectors, which publishes the batches into
+/// their slots.
+pub(crate) trait ProcessBatch {
+    fn process_batch_handle(&self, batch_handle: &mut BatchHandle);
+
+    /// Number of nodes gathered in a batch before it gets published.
+    fn batch_size(&self) -> usize {
+        BATCH_SIZE
+    }
+
+    /// The counters the batches of the collector count their values in, if it keeps any.
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> Option<&Stats> {
+        None
+    }
+}
+
+/// The batch of the current thread for one collector. It holds on to the collector's
+/// shared state, so it can still be published after the collector was moved or dropped.
+#[cfg(feature = "std")]
+struct LocalBatch {
+    collector: Arc<SharedState<dyn ProcessBatch>>,
+    handle: BatchHandle,
+    /// Kept alive by `collector`.
+    #[cfg(feature = "stats")]
+    pending: Option<NonNull<PendingCount>>,
+}
+
+#[cfg(feature = "std")]
+impl LocalBatch {
+    fn new<P: ProcessBatch + 'static>(collector: &SharedState<P>) -> Self {
+        LocalBatch {
+            collector: collector.to_arc(),
+            handle: BatchHandle::new(),
+            #[cfg(feature = "stats")]
+            pending: collector.stats().map(Stats::acquire_pending),
+        }
+    }
+
+    /// Publishes the number of values waiting in the batch.
+    #[inline]
+    fn count_pending(&self) {
+        #[cfg(feature = "stats")]
+        if let Some(pending) = self.pending {
+            unsafe { pending.as_ref() }.set(self.handle.len());
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for LocalBatch {
+    fn drop(&mut self) {
+        // Either the thread is exiting or the collector is gone, in which case no thread can
+        // be pinned in its slots and the batch is freed right away.
+        if let Some(filled_handle) = self.handle.take_filled() {
+            filled_handle.publish(&**self.collector);
+        }
+        #[cfg(feature = "stats")]
+        if let Some(pending) = self.pending {
+            unsafe { pending.as_ref() }.release();
+        }
+    }
 }
 
-const BATCH_SIZE: usize = 64;
+/// Without thread local storage the threads share one batch per collector. A thread that
+/// finds it taken by another one publishes its value in a batch of its own.
+#[cfg(not(feature = "std"))]
+pub(crate) struct SharedBatch {
+    locked: AtomicBool,
+    handle: UnsafeCell<BatchHandle>,
+    /// Acquired on first use, the stats of the collector outlive the batch.
+    #[cfg(feature = "stats")]
+    pending: UnsafeCell<Option<NonNull<PendingCount>>>,
+}
+
+// The batch is only accessed while holding `locked`.
+#[cfg(not(feature = "std"))]
+unsafe impl Send for SharedBatch {}
+#[cfg(not(feature = "std"))]
+unsafe impl Sync for SharedBatch {}
+
+#[cfg(not(feature = "std"))]
+impl SharedBatch {
+    pub(crate) fn new() -> Self {
+        SharedBatch {
+            locked: AtomicBool::new(false),
+            handle: UnsafeCell::new(BatchHandle::new()),
+            #[cfg(feature = "stats")]
+            pending: UnsafeCell::new(None),
+        }
+    }
+
+    /// Runs `f` on the batch, unless another thread is using it.
+    fn try_with<P, F, R>(&self, _collector: &P, f: F) -> Option<R>
+    where
+        P: ProcessBatch + ?Sized,
+        F: FnOnce(&mut BatchHandle) -> R,
+    {
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let handle = unsafe { &mut *self.handle.get() };
+        let res = f(handle);
+        #[cfg(feature = "stats")]
+        if let Some(stats) = _collector.stats() {
+            let pending = unsafe { &mut *self.pending.get() };
+            let pending = pending.get_or_insert_with(|| stats.acquire_pending());
+            unsafe { pending.as_ref() }.set(handle.len());
+        }
+        self.locked.store(false, Ordering::Release);
+        Some(res)
+    }
+}
+
+/// Publishes the batch of the current thread for the dropped collector identified by `key`.
+#[cfg(feature = "std")]
+pub(crate) fn release_local(key: *const ()) {
+    let released = LOCAL_BATCHES
+        .try_with(|b| {
+            let mut batches = b.borrow_mut();
+            let pos = batches.iter().position(|b| b.collector.key() == key)?;
+            Some(batches.swap_remove(pos))
+        })
+        .ok()
+        .flatten();
+    // Released after the thread local, as running the deferred functions may retire again.
+    drop(released);
+}
 
 unsafe impl Send for BatchHandle {}
 
 #[derive(Debug)]
 pub(crate) struct BatchHandle {
     batch: *mut Batch,
-    collector: *const Collector,
 }
 
 impl BatchHandle {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         let res = Box::new(Batch::default());
         BatchHandle {
             batch: Box::into_raw(res),
-            collector: std::ptr::null(),
         }
     }
-    pub(crate) fn add_to_batch(collector: &Collector, val: Node) {
-        LOCAL_BATCH.with(|b| {
-            let mut handle = b.borrow_mut();
-            handle.set_collector(collector);
-            //This is safe because the batch pointer is always initialized when accesing the thread
-            //local see new(). Also no other thread can access the batch as its local to each thread
-            let res = unsafe { (*handle.batch).add(val) };
-            if let Err(res_val) = res {
-                let _filled_handle = BatchHandle {
-                    batch: handle.batch,
-                    collector: handle.collector,
+
+    /// Runs `f` on the current thread's batch for `collector`. Returns `None` if the
+    /// thread local storage is already destroyed.
+    #[cfg(feature = "std")]
+    fn with_local<P, F, R>(collector: &SharedState<P>, f: F) -> Option<R>
+    where
+        P: ProcessBatch + 'static,
+        F: FnOnce(&mut BatchHandle) -> R,
+    {
+        let mut dead_batches = Vec::new();
+        // The batches of dropped collectors are freed once the thread local is released,
+        // as running their deferred functions may retire again.
+        let res = LOCAL_BATCHES
+            .try_with(|b| {
+                let mut batches = b.borrow_mut();
+                let mut i = 0;
+                while i < batches.len() {
+                    if batches[i].collector.is_alive() {
+                        i += 1;
+                    } else {
+                        dead_batches.push(batches.swap_remove(i));
+                    }
+                }
+                let key = collector.key();
+                let pos = match batches.iter().position(|b| b.collector.key() == key) {
+                    Some(pos) => pos,
+                    None => {
+                        batches.push(LocalBatch::new(collector));
+                        batches.len() - 1
+                    }
                 };
+                let res = f(&mut batches[pos].handle);
+                batches[pos].count_pending();
+                res
+            })
+            .ok();
+        drop(dead_batches);
+        res
+    }
 
-                handle.batch = Box::into_raw(Box::new(Batch::default()));
+    /// Without thread local storage the batch is shared by the threads, see [`SharedBatch`].
+    /// Returns `None` if another thread is using it.
+    #[cfg(not(feature = "std"))]
+    fn with_local<P, F, R>(collector: &SharedState<P>, f: F) -> Option<R>
+    where
+        P: ProcessBatch + 'static,
+        F: FnOnce(&mut BatchHandle) -> R,
+    {
+        collector.batch().try_with(&**collector, f)
+    }
 
-                unsafe {
-                    let _ = (*handle.batch).add(res_val).unwrap();
-                };
-            }
-        })
+    pub(crate) fn add_to_batch<P: ProcessBatch + 'static>(collector: &SharedState<P>, val: Node) {
+        let batch_size = collector.batch_size();
+        let mut val = Some(val);
+        // The filled batch is published after the thread local is released, as running the
+        // deferred functions of freed batches may retire again.
+        let filled_handle = BatchHandle::with_local(collector, |handle| {
+            handle.add(val.take().unwrap(), batch_size)
+        });
+        if let Some(Some(filled_handle)) = filled_handle {
+            filled_handle.publish(&**collector);
+        }
+        // The thread is exiting or, without `std`, another thread is using the shared batch.
+        // Publish the value in a batch of its own.
+        if let Some(val) = val {
+            let handle = BatchHandle::new();
+            unsafe {
+                (*handle.batch).add(val, batch_size).unwrap();
+            };
+            handle.publish(&**collector);
+        }
     }
 
-    fn is_full() -> bool {
-        LOCAL_BATCH.with(|b| -> bool { unsafe { (*b.borrow().batch).is_full() } })
+    /// Adds `val` to the batch. If the batch is full, it is replaced by a new one and returned
+    /// to be published.
+    pub(crate) fn add(&mut self, val: Node, batch_size: usize) -> Option<BatchHandle> {
+        //This is safe because the batch pointer is always initialized, see new(). Also no other
+        //thread can access the batch as it is local to a thread or a local handle
+        let res = unsafe { (*self.batch).add(val, batch_size) };
+        if let Err(res_val) = res {
+            let filled_handle = self.take_batch();
+
+            unsafe {
+                (*self.batch).add(res_val, batch_size).unwrap();
+            };
+            Some(filled_handle)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the batch to be published if it is not empty, see [`BatchHandle::add`].
+    pub(crate) fn take_filled(&mut self) -> Option<BatchHandle> {
+        if self.len() == 0 {
+            return None;
+        }
+        Some(self.take_batch())
+    }
+
+    /// Publishes the partially filled batch of the current thread.
+    pub(crate) fn flush<P: ProcessBatch + 'static>(collector: &SharedState<P>) {
+        let flushed_handle = BatchHandle::with_local(collector, |handle| handle.take_filled());
+        if let Some(Some(flushed_handle)) = flushed_handle {
+            flushed_handle.publish(&**collector);
+        }
+        #[cfg(feature = "std")]
+        affine::drain();
+    }
+
+    /// Hands the batch over to `collector`, which frees it once no pinned thread can
+    /// reference its nodes anymore.
+    pub(crate) fn publish<P: ProcessBatch + ?Sized>(mut self, collector: &P) {
+        if self.len() > 0 {
+            collector.process_batch_handle(&mut self);
+            core::mem::forget(self);
+        }
+    }
+
+    /// Replaces the batch with an empty one. The returned handle has to be published.
+    fn take_batch(&mut self) -> BatchHandle {
+        core::mem::replace(self, BatchHandle::new())
     }
 
-    fn get_size() -> usize {
-        LOCAL_BATCH.with(|b| -> usize { unsafe { (*b.borrow().batch).get_size() } })
+    fn is_full<P: ProcessBatch + 'static>(collector: &SharedState<P>, batch_size: usize) -> bool {
+        BatchHandle::with_local(collector, |handle| unsafe {
+            (*handle.batch).is_full(batch_size)
+        })
+        .unwrap()
     }
 
-    fn current_iter() -> Iter<'static> {
-        LOCAL_BATCH.with(|b| -> Iter<'_> { unsafe { (*b.borrow().batch).iter() } })
+    fn get_size<P: ProcessBatch + 'static>(collector: &SharedState<P>) -> usize {
+        BatchHandle::with_local(collector, |handle| handle.len()).unwrap()
     }
 
     pub(crate) fn get_node_nref(&self) -> Option<NonNull<Node>> {
@@ -72,50 +321,60 @@ impl BatchHandle {
             .map(|input| NonNull::from(input.as_ref()))
     }
 
-    pub(crate) fn iter(&self) -> Iter<'_> {
-        unsafe { (*self.batch).iter() }
+    /// Iterates over the first `len` nodes of the batch, filler nodes are added
+    /// if the batch has fewer nodes.
+    pub(crate) fn iter(&self, len: usize) -> Iter<'_> {
+        unsafe { (*self.batch).iter(len) }
     }
 
-    fn set_collector(&mut self, collector: &Collector) {
-        if self.collector.is_null() {
-            self.collector = collector;
-        }
+    pub(crate) fn get_min_birth(&self) -> usize {
+        unsafe { (*self.batch).min_birth }
+    }
+
+    /// Number of retired values in the batch, not counting the filler nodes.
+    pub(crate) fn len(&self) -> usize {
+        unsafe { (*self.batch).get_size() }
+    }
+
+    /// Makes the batch count itself as freed in `stats`. Must be called before the batch is
+    /// published, `stats` has to outlive every thread that can be pinned in the collector.
+    #[cfg(feature = "stats")]
+    pub(crate) fn set_stats(&mut self, stats: &Stats) {
+        unsafe { (*self.batch).stats = Some(NonNull::from(stats)) };
     }
 }
 
 impl Drop for BatchHandle {
     fn drop(&mut self) {
-        // This is safe because we null check the pointer and the pointer will always
-        //point to the batch's active collector and the collector is of static scope or
-        // it outlives the rest of the program.
-        unsafe {
-            if let Some(coll) = self.collector.as_ref() {
-                coll.process_batch_handle(self);
-            }
+        // Batches with nodes are handed over to their collector by `publish`. One that is
+        // dropped instead, for example while unwinding, is leaked as its nodes may still be
+        // referenced.
+        if self.len() == 0 {
+            drop(unsafe { Box::from_raw(self.batch) });
         }
     }
 }
+
 pub(crate) struct Batch {
     first_node: Option<Box<Node>>,
     size: usize,
     nref: AtomicUsize,
+    min_birth: usize,
+    #[cfg(feature = "stats")]
+    stats: Option<NonNull<Stats>>,
 }
 
 impl Batch {
     fn new() -> Self {
-        Batch {
-            first_node: None,
-            size: 0,
-            nref: AtomicUsize::new(0),
-        }
+        Batch::default()
     }
 
-    fn iter(&mut self) -> Iter<'_> {
-        let mut len: usize = 0;
-        let current_node = self.first_node.as_ref().map(|input| {
-            len = BATCH_SIZE;
-            NonNull::from(input.as_ref())
-        });
+    fn iter(&mut self, len: usize) -> Iter<'_> {
+        let current_node = self
+            .first_node
+            .as_ref()
+            .map(|input| NonNull::from(input.as_ref()));
+        let len = if current_node.is_some() { len } else { 0 };
         Iter {
             current_node,
             len,
@@ -123,8 +382,9 @@ impl Batch {
         }
     }
 
-    fn add(&mut self, mut val: Node) -> Result<(), Node> {
-        if !self.is_full() {
+    fn add(&mut self, mut val: Node, batch_size: usize) -> Result<(), Node> {
+        if !self.is_full(batch_size) {
+            self.min_birth = self.min_birth.min(val.get_birth_era());
             val.set_nref_node(NonNull::new(self));
             val.set_batch(self.first_node.take());
             self.first_node = Some(Box::new(val));
@@ -135,8 +395,8 @@ impl Batch {
         }
     }
 
-    fn is_full(&self) -> bool {
-        if self.size == BATCH_SIZE {
+    fn is_full(&self, batch_size: usize) -> bool {
+        if self.size >= batch_size {
             return true;
         }
         false
@@ -161,6 +421,33 @@ impl Default for Batch {
             first_node: None,
             size: 0,
             nref: AtomicUsize::new(0),
+            min_birth: usize::MAX,
+            #[cfg(feature = "stats")]
+            stats: None,
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl Drop for Batch {
+    fn drop(&mut self) {
+        if let Some(stats) = self.stats {
+            // Published batches are freed by the threads unpinning from the collector, which
+            // is therefore still alive.
+            let stats = unsafe { stats.as_ref() };
+            stats.freed(self.size);
+            // The nodes are dropped right after, one clock read is enough for the batch.
+            #[cfg(feature = "latency")]
+            {
+                let now = std::time::Instant::now();
+                let mut node = self.first_node.as_deref();
+                while let Some(val) = node {
+                    if let Some(retired_at) = val.retired_at() {
+                        stats.record_latency(now.saturating_duration_since(retired_at));
+                    }
+                    node = val.next_in_batch();
+                }
+            }
         }
     }
 }
@@ -195,45 +482,73 @@ impl<'a> Iterator for Iter<'a> {
 #[cfg(all(test, not(loom)))]
 mod tests {
 
-    use crate::{node::Node, Collector};
+    use crate::node::Node;
+    #[cfg(all(feature = "std", feature = "double-width"))]
+    use crate::Collector;
 
-    use super::{Batch, BatchHandle, BATCH_SIZE};
+    #[cfg(all(feature = "std", feature = "double-width"))]
+    use super::BatchHandle;
+    use super::{Batch, BATCH_SIZE};
 
+    #[cfg(all(feature = "std", feature = "double-width"))]
     static COLLECTOR: Collector = Collector::new();
 
     fn node_producer(i: usize) -> Node {
-        if i % 2 == 0 {
+        if i.is_multiple_of(2) {
             Node::new(Box::new(i))
         } else {
             Node::new(Box::new("er"))
         }
     }
 
+    #[cfg(all(feature = "std", feature = "double-width"))]
     #[test]
     fn basic_batch_test() {
         for i in 1..2 * BATCH_SIZE {
-            BatchHandle::add_to_batch(&COLLECTOR, node_producer(i));
+            BatchHandle::add_to_batch(COLLECTOR.state(), node_producer(i));
             if i % BATCH_SIZE != 0 {
-                assert!(!BatchHandle::is_full());
-                assert!(BatchHandle::get_size() == i % BATCH_SIZE);
+                assert!(!BatchHandle::is_full(COLLECTOR.state(), BATCH_SIZE));
+                assert!(BatchHandle::get_size(COLLECTOR.state()) == i % BATCH_SIZE);
             } else {
-                assert!(BatchHandle::is_full());
-                assert!(BatchHandle::get_size() == BATCH_SIZE)
+                assert!(BatchHandle::is_full(COLLECTOR.state(), BATCH_SIZE));
+                assert!(BatchHandle::get_size(COLLECTOR.state()) == BATCH_SIZE)
             }
         }
     }
 
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn shared_batch() {
+        use crate::test_util::DropCounter;
+        use crate::{SingleWidthCollector, Smr};
+
+        let drops = DropCounter::new();
+        let collector = SingleWidthCollector::new();
+        {
+            let guard = collector.pin();
+            for _i in 0..BATCH_SIZE / 2 {
+                unsafe {
+                    collector.retire(drops.boxed(), &guard);
+                }
+            }
+        }
+        // The values wait in the batch shared by the threads until it is full or published.
+        assert_eq!(drops.count(), 0);
+        drop(collector);
+        assert_eq!(drops.count(), BATCH_SIZE / 2);
+    }
+
     #[test]
     fn full_iterator_test() {
         let mut batch = Batch::default();
         for i in 1..BATCH_SIZE + 3 {
-            let res = batch.add(node_producer(i));
+            let res = batch.add(node_producer(i), BATCH_SIZE);
             if i == BATCH_SIZE + 1 {
                 assert!(res.is_err());
                 break;
             }
         }
-        let batch_iter = batch.iter();
+        let batch_iter = batch.iter(BATCH_SIZE);
         let mut count = 0;
         for node in batch_iter {
             count += 1;
@@ -250,10 +565,10 @@ mod tests {
     fn partial_iterator_test() {
         let mut batch = Batch::default();
         for i in 1..BATCH_SIZE / 2 {
-            let res = batch.add(node_producer(i));
+            let res = batch.add(node_producer(i), BATCH_SIZE);
             assert!(res.is_ok());
         }
-        let batch_iter = batch.iter();
+        let batch_iter = batch.iter(BATCH_SIZE);
         let mut count = 0;
         for node in batch_iter {
             count += 1;
@@ -265,4 +580,40 @@ mod tests {
         }
         assert_eq!(count, BATCH_SIZE);
     }
+
+    #[cfg(all(feature = "std", feature = "double-width"))]
+    #[test]
+    fn batch_per_collector() {
+        use crate::test_util::DropCounter;
+        use crate::Smr;
+        use std::thread;
+
+        static PINNED_COLLECTOR: Collector = Collector::new();
+        static OTHER_COLLECTOR: Collector = Collector::new();
+        let drops = DropCounter::new();
+
+        let pinned_guard = PINNED_COLLECTOR.pin();
+        let thread_drops = drops.clone();
+        thread::spawn(move || {
+            let guard = PINNED_COLLECTOR.pin();
+            BatchHandle::add_to_batch(PINNED_COLLECTOR.state(), node_producer(0));
+            drop(guard);
+
+            // Nobody is pinned in this collector, so its garbage must not end up behind
+            // the guard held in the other one.
+            let guard = OTHER_COLLECTOR.pin();
+            for _i in 0..3 {
+                unsafe {
+                    OTHER_COLLECTOR.retire(thread_drops.boxed(), &guard);
+                }
+            }
+            drop(guard);
+            OTHER_COLLECTOR.flush();
+            assert_eq!(BatchHandle::get_size(PINNED_COLLECTOR.state()), 1);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(drops.count(), 3);
+        drop(pinned_guard);
+    }
 }
@@ -0,0 +1,131 @@
+//! Runtime configuration of a [`Collector`].
+
+use crate::collector::{checked_config, Collector, SLOTS_LENGTH};
+use crate::slot::SlotStrategy;
+
+/// Builds a heap backed [`Collector`] with a custom number of slots and batch size.
+///
+/// ```
+/// use hyaline_smr::CollectorBuilder;
+///
+/// let collector = CollectorBuilder::new().slots(8).batch_size(16).build();
+/// assert_eq!(collector.slots_length(), 8);
+/// assert_eq!(collector.batch_size(), 16);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CollectorBuilder {
+    slots: usize,
+    batch_size: Option<usize>,
+    strategy: SlotStrategy,
+}
+
+impl CollectorBuilder {
+    /// Creates a builder with one slot per available hardware thread. Without the `std`
+    /// feature the number of hardware threads is unknown and the builder starts with 64 slots.
+    pub fn new() -> Self {
+        #[cfg(feature = "std")]
+        let slots = std::thread::available_parallelism()
+            .map(|val| val.get())
+            .unwrap_or(SLOTS_LENGTH);
+        #[cfg(not(feature = "std"))]
+        let slots = SLOTS_LENGTH;
+        CollectorBuilder {
+            slots,
+            batch_size: None,
+            strategy: SlotStrategy::default(),
+        }
+    }
+
+    /// Sets the number of slots the pinned threads are spread over. The value is rounded
+    /// up to the next power of two when the collector is built.
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Sets the number of retired values a thread gathers before publishing them to the slots.
+    /// Defaults to the larger of 64 and the number of slots. Batches smaller than the number
+    /// of slots are padded with empty nodes when they are published.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Sets how pinning threads are mapped to slots. Defaults to [`SlotStrategy::RoundRobin`].
+    pub fn slot_strategy(mut self, strategy: SlotStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Builds the collector.
+    ///
+    /// # Panics
+    /// Panics if the batch size is zero, or if the number of slots rounded up to the next
+    /// power of two does not fit into a `usize`.
+    pub fn build(self) -> Collector {
+        let (slots, batch_size) = checked_config(self.slots, self.batch_size);
+        Collector::with_config(slots, batch_size, self.strategy)
+    }
+}
+
+impl Default for CollectorBuilder {
+    fn default() -> Self {
+        CollectorBuilder::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use crate::test_util::DropCounter;
+    use crate::{CollectorBuilder, Smr};
+
+    #[test]
+    fn rounds_slots() {
+        let collector = CollectorBuilder::new().slots(6).build();
+        assert_eq!(collector.slots_length(), 8);
+        assert_eq!(collector.batch_size(), 64);
+        let collector = CollectorBuilder::new().slots(256).build();
+        assert_eq!(collector.batch_size(), 256);
+    }
+
+    #[test]
+    #[should_panic(expected = "number of slots of a collector is too large")]
+    fn too_many_slots() {
+        CollectorBuilder::new().slots(usize::MAX).build();
+    }
+
+    #[test]
+    fn count_drop() {
+        for &(slots, batch_size) in &[(1, 1), (2, 7), (16, 3), (128, 64)] {
+            let drops = DropCounter::new();
+            let collector = CollectorBuilder::new()
+                .slots(slots)
+                .batch_size(batch_size)
+                .build();
+            thread::scope(|s| {
+                let handles: Vec<_> = (0..4)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let guard = collector.pin();
+                            for _j in 0..1000 {
+                                unsafe {
+                                    collector.retire(drops.boxed(), &guard);
+                                }
+                            }
+                        })
+                    })
+                    .collect();
+                // Joining explicitly waits for the thread local batches to be published.
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            });
+            // Without `std` the threads share a batch, which is only published once full.
+            #[cfg(not(feature = "std"))]
+            collector.flush();
+            assert_eq!(drops.count(), 4000);
+        }
+    }
+}
@@ -1,113 +1,117 @@
-use std::ptr::NonNull;
+use alloc::alloc::{dealloc, Layout};
+use alloc::boxed::Box;
+#[cfg(feature = "watchdog")]
+use alloc::{sync::Arc, vec::Vec};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, Ordering};
+#[cfg(feature = "watchdog")]
+use core::time::Duration;
 
-use crate::batch::BatchHandle;
+#[cfg(feature = "std")]
+use crate::affine;
+use crate::batch::BATCH_SIZE;
+#[cfg(feature = "double-width")]
+use crate::batch::{BatchHandle, ProcessBatch};
+#[cfg(feature = "double-width")]
+use crate::builder::CollectorBuilder;
 use crate::guard::Guard;
+#[cfg(feature = "double-width")]
 use crate::headnode::HeadNode;
+#[cfg(feature = "double-width")]
+use crate::local::{self, LocalHandle};
+#[cfg(feature = "double-width")]
 use crate::node::Node;
+#[cfg(feature = "double-width")]
+use crate::shared::{SharedCell, SharedState};
+#[cfg(feature = "double-width")]
+use crate::slot::SlotStrategy;
+#[cfg(feature = "stats")]
+use crate::stats::{CollectorStats, Stats};
+#[cfg(feature = "watchdog")]
+use crate::watchdog::{StalledGuard, Watchdog};
 
-use crate::primitive::thread;
+pub(crate) const SLOTS_LENGTH: usize = 64;
 
-const SLOTS_LENGTH: usize = 64;
+pub(crate) const ADJS: usize = adjs(SLOTS_LENGTH);
 
-pub(crate) const ADJS: usize = (usize::MAX / SLOTS_LENGTH).wrapping_add(1);
+/// The adjustment each slot contributes to a batch's reference count. The contributions of
+/// all the slots add up to zero, which requires `slots_length` to be a power of two.
+pub(crate) const fn adjs(slots_length: usize) -> usize {
+    (usize::MAX / slots_length).wrapping_add(1)
+}
 
-/// Garbage collector that implements Hyaline algorithm
-/// Number of slots is fixed at present with 32 slots
+/// Rounds the number of slots of a builder up to a power of two and picks the batch size,
+/// by default the larger of 64 and the number of slots.
+pub(crate) fn checked_config(slots: usize, batch_size: Option<usize>) -> (usize, usize) {
+    let slots = slots
+        .max(1)
+        .checked_next_power_of_two()
+        .expect("number of slots of a collector is too large");
+    let batch_size = batch_size.unwrap_or_else(|| BATCH_SIZE.max(slots));
+    assert!(batch_size > 0, "batch size of a collector must not be zero");
+    (slots, batch_size)
+}
+
+#[cfg(feature = "double-width")]
+/// The slots of a [`Collector`] and everything needed to publish a batch into them.
 #[derive(Debug)]
-pub struct Collector {
-    slots: [HeadNode; SLOTS_LENGTH],
+pub(crate) struct CollectorState {
+    slots: Box<[HeadNode]>,
+    adjs: usize,
+    batch_size: usize,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    #[cfg(feature = "watchdog")]
+    watchdog: Watchdog,
 }
 
-impl Collector {
-    /// Creates a new collector with default configurations.
-    ///
-    /// It is absolutely essential for the collector obtained here to live longer than
-    /// all the threads that use it. Preferrably use this function to initialize a collector in static scope
-    /// or if possible use scoped threads.
-    #[rustfmt::skip]
-    pub const fn new() -> Self {
-        Collector {
-            slots: [
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                HeadNode::new(None, 0),
-                ],
-        }
-    }
-
-    fn get_slot() -> usize {
-        let thread_id: usize = thread::current().id().as_u64().get() as usize;
-        thread_id % SLOTS_LENGTH
-    }
-
-    pub(crate) fn process_batch_handle(&self, batch_handle: &mut BatchHandle) {
-        let mut batch_iter = batch_handle.iter();
+#[cfg(feature = "double-width")]
+impl CollectorState {
+    fn new(slots_length: usize, batch_size: usize) -> Self {
+        CollectorState {
+            slots: (0..slots_length).map(|_| HeadNode::new(None, 0)).collect(),
+            adjs: adjs(slots_length),
+            batch_size,
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+            #[cfg(feature = "watchdog")]
+            watchdog: Watchdog::new(),
+        }
+    }
+
+    pub(crate) fn slot_head(&self, slot: usize) -> &HeadNode {
+        &self.slots[slot]
+    }
+
+    pub(crate) fn adjs(&self) -> usize {
+        self.adjs
+    }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    #[cfg(feature = "watchdog")]
+    pub(crate) fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+}
+
+#[cfg(feature = "double-width")]
+impl ProcessBatch for CollectorState {
+    fn process_batch_handle(&self, batch_handle: &mut BatchHandle) {
+        #[cfg(feature = "stats")]
+        {
+            self.stats.published(batch_handle.len());
+            batch_handle.set_stats(&self.stats);
+        }
+        let mut batch_iter = batch_handle.iter(self.slots.len());
         let mut empty_slots: usize = 0;
         let node_nref = batch_handle.get_node_nref();
         for slot in self.slots.iter() {
             if let Some(mut val) = batch_iter.next() {
-                let add_result = unsafe { slot.add_to_slot(val.as_mut()) };
+                let add_result = unsafe { slot.add_to_slot(val.as_mut(), self.adjs) };
                 match add_result {
                     Ok(_) => {}
                     Err(_) => empty_slots += 1,
@@ -116,12 +120,226 @@ impl Collector {
         }
         if empty_slots > 0 {
             unsafe {
-                Node::add_to_nref(node_nref, empty_slots.wrapping_mul(ADJS));
+                Node::add_to_nref(node_nref, empty_slots.wrapping_mul(self.adjs));
             };
         }
     }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> Option<&Stats> {
+        Some(&self.stats)
+    }
+}
+
+#[cfg(feature = "double-width")]
+/// Garbage collector that implements Hyaline algorithm
+///
+/// The number of slots has to be a power of two. [`Collector::new`] creates a collector with
+/// 64 slots and [`Collector::with_slots`] with the given number of slots, both can be used in
+/// a `static`. Use [`CollectorBuilder`](crate::CollectorBuilder) to configure the batch size
+/// and the slot strategy as well.
+///
+/// ```
+/// use hyaline_smr::{Collector, Smr};
+///
+/// static COLLECTOR: Collector = Collector::with_slots(8);
+///
+/// let guard = COLLECTOR.pin();
+/// assert_eq!(COLLECTOR.slots_length(), 8);
+/// ```
+#[derive(Debug)]
+pub struct Collector {
+    slots_length: usize,
+    batch_size: usize,
+    strategy: SlotStrategy,
+    state: SharedCell<CollectorState>,
+}
+
+#[cfg(feature = "double-width")]
+impl Collector {
+    /// Creates a new collector with default configurations.
+    ///
+    /// The collector can be kept in a `static` or owned, for example in an `Arc`, and may be
+    /// moved freely. Its slots are allocated the first time it is used. Dropping it frees
+    /// all the garbage that was retired into it.
+    pub const fn new() -> Self {
+        Collector::with_slots(SLOTS_LENGTH)
+    }
+
+    /// Creates a new collector with `slots` slots, otherwise the same as [`Collector::new`].
+    ///
+    /// # Panics
+    /// Panics if `slots` is not a power of two, at compile time when used in a `static`.
+    pub const fn with_slots(slots: usize) -> Self {
+        assert!(
+            slots.is_power_of_two(),
+            "number of slots of a collector must be a power of two"
+        );
+        Collector {
+            slots_length: slots,
+            batch_size: BATCH_SIZE,
+            strategy: SlotStrategy::RoundRobin,
+            state: SharedCell::new(),
+        }
+    }
+
+    /// Returns a builder to configure the number of slots and the batch size of a collector.
+    pub fn builder() -> CollectorBuilder {
+        CollectorBuilder::new()
+    }
+
+    pub(crate) fn with_config(
+        slots_length: usize,
+        batch_size: usize,
+        strategy: SlotStrategy,
+    ) -> Self {
+        debug_assert!(slots_length.is_power_of_two());
+        Collector {
+            slots_length,
+            batch_size,
+            strategy,
+            state: SharedCell::new(),
+        }
+    }
+
+    /// Returns the number of slots of this collector.
+    pub fn slots_length(&self) -> usize {
+        self.slots_length
+    }
+
+    /// Returns the number of retired values a thread gathers before publishing them.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Returns the strategy used to pick the slot of a pinning thread.
+    pub fn slot_strategy(&self) -> SlotStrategy {
+        self.strategy
+    }
+
+    /// Pins the current thread in the given slot instead of the one picked by the
+    /// collector's [`SlotStrategy`]. Threads bound to a core can use the core's index so
+    /// that they never share a slot. `slot` is taken modulo the number of slots. If the
+    /// thread is already pinned, the guard shares the slot of the outer guard.
+    ///
+    /// Repinning the guard pins it in the same slot again.
+    pub fn pin_in_slot(&self, slot: usize) -> Guard<'_, Self> {
+        let mut guard = local::pin(self, || self.pin_slot(slot));
+        guard.pinned_in = Some((slot, Collector::pin_in_slot));
+        guard
+    }
+
+    /// Registers an execution context, like a thread or a CPU, which pins through the
+    /// returned handle instead of thread local storage. The slot of the handle is picked by
+    /// the collector's [`SlotStrategy`]. See [`LocalHandle`].
+    pub fn register(&self) -> LocalHandle<'_> {
+        self.register_in_slot(self.strategy.slot(self.slots_length()))
+    }
+
+    /// Same as [`Collector::register`], with the guards of the handle pinned in the given
+    /// slot. `slot` is taken modulo the number of slots.
+    pub fn register_in_slot(&self, slot: usize) -> LocalHandle<'_> {
+        LocalHandle::new(self, slot % self.slots_length())
+    }
+
+    /// Returns a snapshot of the counters of this collector. Requires the `stats` feature.
+    ///
+    /// ```
+    /// use hyaline_smr::{Collector, Smr};
+    ///
+    /// let collector = Collector::new();
+    /// let guard = collector.pin();
+    /// collector.retire_box(Box::new(1), &guard);
+    ///
+    /// let stats = collector.stats();
+    /// assert_eq!(stats.retired, 1);
+    /// assert_eq!(stats.pending, 1);
+    /// assert_eq!(stats.pinned.iter().sum::<usize>(), 1);
+    /// ```
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> CollectorStats {
+        let state = self.state();
+        state
+            .stats
+            .snapshot(state.slots.iter().map(HeadNode::pinned).collect())
+    }
+
+    /// Returns the guards which have been held for at least `threshold`, with the slot and
+    /// the thread they were pinned in. Nested guards are reported once, with the time of
+    /// the outermost guard. Requires the `watchdog` feature.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hyaline_smr::{Collector, Smr};
+    ///
+    /// let collector = Collector::new();
+    /// let _guard = collector.pin();
+    /// let _nested = collector.pin();
+    ///
+    /// let stalled = collector.long_lived_guards(Duration::ZERO);
+    /// assert_eq!(stalled.len(), 1);
+    /// assert!(collector.long_lived_guards(Duration::from_secs(60)).is_empty());
+    /// ```
+    #[cfg(feature = "watchdog")]
+    pub fn long_lived_guards(&self, threshold: Duration) -> Vec<StalledGuard> {
+        let state = self.state();
+        state.watchdog.long_lived(threshold, &state.slots)
+    }
+
+    /// Sets a hook which is called once for every guard held for at least `threshold`,
+    /// replacing the previous one. The guards are only checked by
+    /// [`check_stalled_guards`](Collector::check_stalled_guards), which has to be called
+    /// periodically, for example from a monitoring thread. Requires the `watchdog` feature.
+    #[cfg(feature = "watchdog")]
+    pub fn on_stalled_guard<F>(&self, threshold: Duration, hook: F)
+    where
+        F: Fn(&StalledGuard) + Send + Sync + 'static,
+    {
+        self.state().watchdog.set_hook(threshold, Arc::new(hook));
+    }
+
+    /// Calls the hook set by [`on_stalled_guard`](Collector::on_stalled_guard) on the current
+    /// thread for the guards which became stalled since the last check. Does nothing if no
+    /// hook is set. The pinned threads are not slowed down by the check, apart from the first
+    /// pin of a thread waiting for the lock of the records. Requires the `watchdog` feature.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use hyaline_smr::{Collector, Smr};
+    ///
+    /// let collector = Collector::new();
+    /// collector.on_stalled_guard(Duration::ZERO, |guard| println!("stalled in {}", guard.slot));
+    /// let _guard = collector.pin();
+    /// collector.check_stalled_guards();
+    /// ```
+    #[cfg(feature = "watchdog")]
+    pub fn check_stalled_guards(&self) {
+        let state = self.state();
+        state.watchdog.check(&state.slots);
+    }
+
+    /// Returns the slots of the collector, allocating them on first use.
+    #[inline]
+    pub(crate) fn state(&self) -> &SharedState<CollectorState> {
+        self.state
+            .get_or_init(|| CollectorState::new(self.slots_length, self.batch_size))
+    }
+
+    fn pin_slot(&self, slot: usize) -> (usize, Option<&'static Node>) {
+        let state = self.state();
+        let slot = slot % state.slots.len();
+        let handle = state.slots[slot].pin_slot();
+        #[cfg(feature = "watchdog")]
+        state.watchdog.pinned(None, slot, &state.slots[slot]);
+        (slot, handle)
+    }
 }
 
+#[cfg(feature = "double-width")]
 impl Default for Collector {
     fn default() -> Self {
         Collector::new()
@@ -136,44 +354,225 @@ pub trait Smr {
     /// concurrent data structure, has to be performed with a guard in scope.(i.e, Before any operation call the hyaline::pin() method)
     /// This method returna a guard, which takes care of unpining the thread when the guard goes out of scope.
     /// Exact details on what constitutes a register is implementation dependent
-    fn pin(&self) -> Guard<'_>;
+    fn pin(&self) -> Guard<'_, Self>;
 
     /// This is the opposite of pin method. Upon calling this method the thread will be de-registered.
     /// Most implementations dont expose this method to the end user as it it will be put behind a RAII guard.
-    fn unpin(&self, local_guard: &Guard<'_>);
+    fn unpin(&self, local_guard: &Guard<'_, Self>);
+
+    /// Unpins and pins the thread behind `local_guard` again. See [`Guard::repin`].
+    fn repin<'a>(&'a self, local_guard: &mut Guard<'a, Self>) {
+        self.unpin(local_guard);
+        local_guard.pin_again();
+    }
 
     /// Collects the garbage values form the user. The local_guard argument is just here
-    /// for ensuring that retire() is called after a pin().
+    /// for ensuring that retire() is called after a pin(). `garbage` may point to an unsized
+    /// value like a slice or a trait object, as long as it was allocated by a `Box`.
+    /// The value is dropped later by whichever thread unpins last, so it has to be `Send`
+    /// and must not borrow anything.
+    ///
+    /// Without the `std` feature the threads share one batch per collector. A value retired
+    /// while another thread adds to it is published in a batch of its own, which is padded
+    /// to one node per slot.
     ///
     /// # Safety
     /// Caller must ensure that only logically deleted values of the concerned data structure is
     /// provided to the retire method. For example: In a lock-free linkedlist retire() needs to be called
     /// only after the concerned node is removed form the list.
-    unsafe fn retire<T>(&self, garbage: Option<NonNull<T>>, _local_guard: &Guard<'_>);
+    unsafe fn retire<T: ?Sized + Send + 'static>(
+        &self,
+        garbage: Option<NonNull<T>>,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        if let Some(garb) = garbage {
+            let val = Box::from_raw(garb.as_ptr());
+            self.defer_unchecked(move || drop(val), local_guard);
+        }
+    }
+
+    /// Retires a value that was never shared with other threads, so it is dropped
+    /// only after the threads that are currently pinned have unpinned.
+    ///
+    /// Values that are not `Send` are rejected:
+    /// ```compile_fail
+    /// use hyaline_smr::{Collector, Smr};
+    /// use std::rc::Rc;
+    ///
+    /// let collector = Collector::new();
+    /// let guard = collector.pin();
+    /// collector.retire_box(Box::new(Rc::new(1)), &guard);
+    /// ```
+    fn retire_box<T: ?Sized + Send + 'static>(
+        &self,
+        garbage: Box<T>,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        unsafe { self.retire(NonNull::new(Box::into_raw(garbage)), local_guard) }
+    }
+
+    /// Same as [`retire`](Smr::retire) for values that were not allocated by a `Box`, for
+    /// example values taken from a slab or an arena. `destructor` is called with the pointer
+    /// once the value cannot be referenced anymore.
+    ///
+    /// # Safety
+    /// Same as [`retire`](Smr::retire). Additionally `destructor` must be safe to call with
+    /// `garbage` on any thread.
+    unsafe fn retire_with<T: ?Sized>(
+        &self,
+        garbage: NonNull<T>,
+        destructor: unsafe fn(*mut T),
+        local_guard: &Guard<'_, Self>,
+    ) {
+        self.defer_unchecked(move || destructor(garbage.as_ptr()), local_guard);
+    }
+
+    /// Deallocates the memory at `garbage` with the global allocator once it cannot be
+    /// referenced anymore. No destructor is run.
+    ///
+    /// # Safety
+    /// Same as [`retire`](Smr::retire). Additionally `garbage` must have been allocated by the
+    /// global allocator with `layout`.
+    unsafe fn retire_raw(
+        &self,
+        garbage: NonNull<u8>,
+        layout: Layout,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        self.defer_unchecked(
+            move || {
+                if layout.size() != 0 {
+                    dealloc(garbage.as_ptr(), layout);
+                }
+            },
+            local_guard,
+        );
+    }
+
+    /// Same as [`retire`](Smr::retire) for values which have to be dropped on the current
+    /// thread. Once no thread can reference the value anymore it is handed back to this thread,
+    /// which drops it the next time it unpins or flushes. Values still waiting when the
+    /// thread exits are dropped then, values that become safe to drop after that are leaked.
+    ///
+    /// # Safety
+    /// Same as [`retire`](Smr::retire).
+    #[cfg(feature = "std")]
+    unsafe fn retire_local<T: ?Sized + 'static>(
+        &self,
+        garbage: Option<NonNull<T>>,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        if let Some(garb) = garbage {
+            let val = Box::from_raw(garb.as_ptr());
+            self.defer_local(move || drop(val), local_guard);
+        }
+    }
+
+    /// Runs `f` on the current thread once all the threads that are currently pinned have
+    /// unpinned. See [`retire_local`](Smr::retire_local) for when it runs.
+    #[cfg(feature = "std")]
+    fn defer_local<F: FnOnce() + 'static>(&self, f: F, local_guard: &Guard<'_, Self>) {
+        if local_guard.is_unprotected() {
+            f();
+        } else if let Some(bound) = affine::bind_to_thread(f) {
+            unsafe { self.defer_unchecked(bound, local_guard) }
+        }
+    }
+
+    /// Runs `f` once all the threads that are currently pinned have unpinned.
+    /// The closure may run on any thread. See [`Guard::defer`] for a safe version.
+    ///
+    /// # Safety
+    /// `f` may run after everything it borrows is gone and on a different thread than
+    /// the one it was created on. The caller must make sure that this is fine.
+    unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F, local_guard: &Guard<'_, Self>);
+
+    /// Publishes the values retired by the current thread that are still waiting in its
+    /// partially filled batch, so that they can be reclaimed without waiting for more garbage.
+    /// Collectors which do not buffer retired values have nothing to do here.
+    fn flush(&self) {}
+
+    /// Loads a shared pointer on behalf of a pinned thread. Collectors which track what
+    /// a thread may reach (like [`RobustCollector`](crate::RobustCollector)) override this,
+    /// the default is a plain load.
+    #[inline]
+    fn protect<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        order: Ordering,
+        _local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        ptr.load(order)
+    }
+
+    /// Protects `observed`, a value of `ptr` returned by a read-modify-write like a swap or
+    /// a failed compare and exchange, and returns it. Collectors which track what a thread may
+    /// reach return a newer value loaded with `order` if they had to catch up with it, the
+    /// default returns `observed` without touching `ptr`.
+    #[inline]
+    fn protect_observed<T>(
+        &self,
+        _ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        _order: Ordering,
+        _local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        observed
+    }
 }
 
+#[cfg(feature = "double-width")]
 impl Smr for Collector {
-    fn pin(&self) -> Guard<'_> {
-        let mut result_guard = Guard::new(self);
-        result_guard.slot = Collector::get_slot();
-        result_guard.handle = self.slots[result_guard.slot].pin_slot();
-        result_guard
+    fn pin(&self) -> Guard<'_, Self> {
+        local::pin(self, || {
+            self.pin_slot(self.strategy.slot(self.slots_length()))
+        })
     }
 
-    fn unpin(&self, local_guard: &Guard<'_>) {
-        let start = local_guard.slot;
-        self.slots[start].unpin_slot(local_guard);
+    fn unpin(&self, local_guard: &Guard<'_, Self>) {
+        if let Some(local) = local_guard.local {
+            local.unpin(local_guard);
+        } else if local::unpin(self) {
+            let state = self.state();
+            state.slots[local_guard.slot].unpin_slot(local_guard, state.adjs);
+            #[cfg(feature = "watchdog")]
+            state.watchdog.unpinned(None);
+        }
     }
 
-    unsafe fn retire<T>(&self, garbage: Option<NonNull<T>>, _local_guard: &Guard<'_>) {
-        if let Some(garb) = garbage {
-            let garb_node = Node::new(Box::from_raw(garb.as_ptr()));
-            BatchHandle::add_to_batch(self, garb_node);
+    fn repin<'a>(&'a self, local_guard: &mut Guard<'a, Self>) {
+        // Nothing was retired into the slot, unpinning would not free anything.
+        let state = self.state();
+        let head = &state.slots[local_guard.slot];
+        if head.is_head(local_guard.handle) {
+            #[cfg(feature = "watchdog")]
+            state
+                .watchdog
+                .repinned(local_guard.local.map(|local| local.watchdog_record()), head);
+            return;
+        }
+        self.unpin(local_guard);
+        local_guard.pin_again();
+    }
+
+    unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F, local_guard: &Guard<'_, Self>) {
+        if local_guard.is_unprotected() {
+            f();
+            return;
+        }
+        local_guard.check_owner(self);
+        match local_guard.local {
+            Some(local) => local.add_to_batch(Node::with_fn(f)),
+            None => BatchHandle::add_to_batch(self.state(), Node::with_fn(f)),
         }
     }
+
+    fn flush(&self) {
+        BatchHandle::flush(self.state());
+    }
 }
 
-#[cfg(all(test, not(loom)))]
+#[cfg(all(test, not(loom), feature = "double-width"))]
 mod tests {
     use std::{
         ptr::NonNull,
@@ -181,48 +580,416 @@ mod tests {
         thread,
     };
 
+    use crate::test_util::DropCounter;
     use crate::{Collector, Smr};
 
     const MAX_THREADS: usize = 8;
     static COLLECTOR: Collector = Collector::new();
-    static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
-
-    struct TestNode {
-        foo: usize,
-        bar: usize,
-    }
 
-    impl Drop for TestNode {
-        fn drop(&mut self) {
-            DROP_COUNT.fetch_add(1, Ordering::Relaxed);
-        }
-    }
-
-    fn node_producer(i: usize) -> Option<NonNull<TestNode>> {
-        if i % 2 == 0 {
-            let x = Box::new(TestNode { foo: i, bar: i + 1 });
-            NonNull::new(Box::into_raw(x))
-        } else {
-            let y = Box::new(TestNode { foo: 0, bar: 0 });
-            NonNull::new(Box::into_raw(y))
-        }
-    }
     #[test]
     fn count_drop() {
+        let drops = DropCounter::new();
         let mut handle_array = Vec::new();
 
         for _i in 0..MAX_THREADS {
+            let drops = drops.clone();
             let handle = thread::spawn(move || {
                 let guard = COLLECTOR.pin();
-                for j in 0..5000 {
+                for _j in 0..5000 {
                     unsafe {
-                        COLLECTOR.retire(node_producer(j), &guard);
+                        COLLECTOR.retire(drops.boxed(), &guard);
                     }
                 }
             });
             handle_array.push(handle);
         }
-        while DROP_COUNT.load(Ordering::Relaxed) < MAX_THREADS * 5000 {}
-        assert_eq!(DROP_COUNT.load(Ordering::Relaxed), MAX_THREADS * 5000);
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        {
+            for handle in handle_array {
+                handle.join().unwrap();
+            }
+            COLLECTOR.flush();
+        }
+        while drops.count() < MAX_THREADS * 5000 {}
+        assert_eq!(drops.count(), MAX_THREADS * 5000);
+    }
+
+    #[test]
+    #[should_panic(expected = "number of slots of a collector must be a power of two")]
+    fn slots_power_of_two() {
+        Collector::with_slots(6);
+    }
+
+    #[test]
+    fn custom_slots() {
+        static SMALL_COLLECTOR: Collector = Collector::with_slots(2);
+        let drops = DropCounter::new();
+
+        let handle_array: Vec<_> = (0..MAX_THREADS)
+            .map(|_| {
+                let drops = drops.clone();
+                thread::spawn(move || {
+                    let guard = SMALL_COLLECTOR.pin();
+                    assert!(guard.slot < 2);
+                    for _j in 0..1000 {
+                        unsafe {
+                            SMALL_COLLECTOR.retire(drops.boxed(), &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handle_array {
+            handle.join().unwrap();
+        }
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        SMALL_COLLECTOR.flush();
+        assert_eq!(drops.count(), MAX_THREADS * 1000);
+    }
+
+    #[test]
+    fn flush_partial_batch() {
+        static FLUSH_COLLECTOR: Collector = Collector::new();
+        let drops = DropCounter::new();
+
+        thread::spawn(move || {
+            let guard = FLUSH_COLLECTOR.pin();
+            for _j in 0..3 {
+                unsafe {
+                    FLUSH_COLLECTOR.retire(drops.boxed(), &guard);
+                }
+            }
+            guard.flush();
+            // Still protected by our own guard.
+            assert_eq!(drops.count(), 0);
+            drop(guard);
+            assert_eq!(drops.count(), 3);
+
+            let guard = FLUSH_COLLECTOR.pin();
+            unsafe {
+                FLUSH_COLLECTOR.retire(drops.boxed(), &guard);
+            }
+            drop(guard);
+            FLUSH_COLLECTOR.flush();
+            assert_eq!(drops.count(), 4);
+            // Nothing left to publish.
+            FLUSH_COLLECTOR.flush();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn drop_owned_collector() {
+        use std::sync::{mpsc, Arc};
+
+        fn retire_nodes(collector: &Collector, drops: &DropCounter, count: usize) {
+            let guard = collector.pin();
+            for _j in 0..count {
+                unsafe {
+                    collector.retire(drops.boxed(), &guard);
+                }
+            }
+        }
+
+        let drops = DropCounter::new();
+        let collector = Arc::new(Collector::new());
+        let (sender, receiver) = mpsc::channel();
+        let thread_collector = collector.clone();
+        let thread_drops = drops.clone();
+        let handle = thread::spawn(move || {
+            retire_nodes(&thread_collector, &thread_drops, 3);
+            drop(thread_collector);
+            // Keep the batch around until the collector is gone.
+            receiver.recv().unwrap();
+        });
+
+        retire_nodes(&collector, &drops, 2);
+        while Arc::strong_count(&collector) > 1 {
+            thread::yield_now();
+        }
+        drop(collector);
+        assert_eq!(drops.count(), 2);
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(drops.count(), 5);
+    }
+
+    #[test]
+    fn move_collector() {
+        use std::sync::Arc;
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let collector = Collector::new();
+        let guard = collector.pin();
+        for _j in 0..3 {
+            let dropped = dropped.clone();
+            guard.defer(move || {
+                dropped.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        drop(guard);
+
+        // The batch waiting in the thread local moves along with the collector.
+        let moved = Box::new(collector);
+        moved.flush();
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
+
+        // A collector created where the old one was does not pick up its garbage.
+        let guard = moved.pin();
+        let counter = dropped.clone();
+        guard.defer(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        drop(guard);
+        drop(moved);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+        let collector = Collector::new();
+        collector.flush();
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn defer_closure() {
+        use std::sync::Arc;
+
+        let collector = Collector::new();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let guard = collector.pin();
+        for _j in 0..3 {
+            let fired = fired.clone();
+            guard.defer(move || {
+                fired.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        guard.flush();
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+        drop(guard);
+        assert_eq!(fired.load(Ordering::Relaxed), 3);
+
+        // Borrowing is fine as long as the closure runs before the borrow ends.
+        let count = AtomicUsize::new(0);
+        let guard = collector.pin();
+        unsafe {
+            guard.defer_unchecked(|| {
+                count.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        drop(guard);
+        collector.flush();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn retire_with_destructor() {
+        use std::alloc::{alloc, Layout};
+
+        static DESTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe fn destructor(ptr: *mut usize) {
+            DESTRUCTED.fetch_add(*ptr, Ordering::Relaxed);
+            drop(Box::from_raw(ptr));
+        }
+
+        let collector = Collector::new();
+        let guard = collector.pin();
+        unsafe {
+            let garb = NonNull::new_unchecked(Box::into_raw(Box::new(5)));
+            collector.retire_with(garb, destructor, &guard);
+
+            let layout = Layout::array::<u64>(4).unwrap();
+            let raw = NonNull::new(alloc(layout)).unwrap();
+            collector.retire_raw(raw, layout, &guard);
+        }
+        drop(guard);
+        collector.flush();
+        assert_eq!(DESTRUCTED.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn retire_unsized() {
+        use crate::test_util::Counted;
+
+        trait Entry {}
+
+        impl Entry for Counted {}
+
+        let drops = DropCounter::new();
+        let collector = Collector::new();
+        let guard = collector.pin();
+        unsafe {
+            let slice: Box<[Counted]> = vec![drops.counted(), drops.counted()].into_boxed_slice();
+            collector.retire(NonNull::new(Box::into_raw(slice)), &guard);
+            let object: Box<dyn Entry + Send> = Box::new(drops.counted());
+            collector.retire(NonNull::new(Box::into_raw(object)), &guard);
+            let string: Box<str> = "retired".into();
+            collector.retire(NonNull::new(Box::into_raw(string)), &guard);
+        }
+        drop(guard);
+        collector.flush();
+        assert_eq!(drops.count(), 3);
+    }
+
+    #[test]
+    fn retire_box() {
+        let collector = Collector::new();
+        let dropped = std::sync::Arc::new(());
+        let guard = collector.pin();
+        collector.retire_box(Box::new(dropped.clone()), &guard);
+        assert_eq!(std::sync::Arc::strong_count(&dropped), 2);
+        drop(guard);
+        collector.flush();
+        assert_eq!(std::sync::Arc::strong_count(&dropped), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn retire_local() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::sync::mpsc;
+        use std::thread::ThreadId;
+
+        struct LocalNode(Rc<Cell<Option<ThreadId>>>);
+
+        impl Drop for LocalNode {
+            fn drop(&mut self) {
+                self.0.set(Some(thread::current().id()));
+            }
+        }
+
+        let collector = Collector::new();
+        let dropped_on = Rc::new(Cell::new(None));
+        let (pinned_sender, pinned_receiver) = mpsc::channel();
+        let (retired_sender, retired_receiver) = mpsc::channel();
+        thread::scope(|s| {
+            let reader_collector = &collector;
+            let reader = s.spawn(move || {
+                let _guard = reader_collector.pin();
+                pinned_sender.send(()).unwrap();
+                retired_receiver.recv().unwrap();
+                // The reader unpins last and reclaims the node.
+            });
+            pinned_receiver.recv().unwrap();
+            let guard = collector.pin();
+            let garb = Box::new(LocalNode(dropped_on.clone()));
+            unsafe { collector.retire_local(NonNull::new(Box::into_raw(garb)), &guard) };
+            drop(guard);
+            collector.flush();
+            retired_sender.send(()).unwrap();
+            reader.join().unwrap();
+        });
+        assert_eq!(dropped_on.get(), None);
+        drop(collector.pin());
+        assert_eq!(dropped_on.get(), Some(thread::current().id()));
+    }
+
+    #[test]
+    fn repin() {
+        fn retire_nodes(collector: &Collector, drops: &DropCounter) {
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let guard = collector.pin();
+                    for _j in 0..3 {
+                        unsafe {
+                            collector.retire(drops.boxed(), &guard);
+                        }
+                    }
+                    drop(guard);
+                    collector.flush();
+                });
+            });
+        }
+
+        let drops = DropCounter::new();
+        let collector = Collector::new();
+        let mut guard = collector.pin();
+        // Nothing to reclaim yet.
+        guard.repin();
+        retire_nodes(&collector, &drops);
+        assert_eq!(drops.count(), 0);
+        guard.repin();
+        assert_eq!(drops.count(), 3);
+
+        retire_nodes(&collector, &drops);
+        let count = guard.repin_after(|| drops.count());
+        assert_eq!(count, 6);
+        drop(guard);
+    }
+
+    #[test]
+    fn unprotected() {
+        let dropped = std::sync::Arc::new(());
+        let guard = unsafe { crate::unprotected() };
+        crate::default_collector().retire_box(Box::new(dropped.clone()), guard);
+        assert_eq!(std::sync::Arc::strong_count(&dropped), 1);
+        let moved = dropped.clone();
+        guard.defer(move || drop(moved));
+        assert_eq!(std::sync::Arc::strong_count(&dropped), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn nested_pin() {
+        let drops = DropCounter::new();
+        let collector = Collector::new();
+        let outer = collector.pin();
+        let inner = collector.pin();
+        assert_eq!(outer.slot, inner.slot);
+        assert!(outer.is_handle(inner.handle.map(NonNull::from)));
+        thread::scope(|s| {
+            s.spawn(|| {
+                let guard = collector.pin();
+                for _j in 0..3 {
+                    unsafe {
+                        collector.retire(drops.boxed(), &guard);
+                    }
+                }
+                drop(guard);
+                collector.flush();
+            });
+        });
+        // Dropping the outer guard first keeps the thread pinned by the inner one.
+        drop(outer);
+        assert_eq!(drops.count(), 0);
+        drop(inner);
+        assert_eq!(drops.count(), 3);
+    }
+
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    #[test]
+    #[should_panic(expected = "retired with a guard pinned in another collector")]
+    fn retire_with_guard_of_other_collector() {
+        let first = Collector::new();
+        let second = Collector::new();
+        let guard = first.pin();
+        second.retire_box(Box::new(1), &guard);
+    }
+
+    #[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+    #[test]
+    fn retire_with_guard_of_other_thread() {
+        struct SendGuard(crate::Guard<'static>);
+        // Only to smuggle the guard past the compiler, which is what the check catches.
+        unsafe impl Send for SendGuard {}
+
+        let collector: &'static Collector = Box::leak(Box::new(Collector::new()));
+        let guard = thread::spawn(move || SendGuard(collector.pin()))
+            .join()
+            .unwrap();
+        let panic = thread::spawn(move || {
+            let guard = guard;
+            collector.retire_box(Box::new(1), &guard.0);
+        })
+        .join()
+        .unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<&str>(),
+            Some(&"retired with a guard created on another thread")
+        );
     }
 }
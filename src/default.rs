@@ -1,6 +1,6 @@
 //! The default garbage collector.
 
-use std::ptr::NonNull;
+use core::ptr::NonNull;
 
 use crate::collector::{Collector, Smr};
 use crate::guard::Guard;
@@ -22,10 +22,20 @@ pub fn pin() -> Guard<'static> {
 /// provided to the retire method. For example: In a lock-free linkedlist retire() needs to be called
 /// only after the concerned node is removed form the list.
 #[inline]
-pub unsafe fn retire<T>(garbage: Option<NonNull<T>>, local_guard: &Guard<'_>) {
+pub unsafe fn retire<T: ?Sized + Send + 'static>(
+    garbage: Option<NonNull<T>>,
+    local_guard: &Guard<'_>,
+) {
     COLLECTOR.retire(garbage, local_guard);
 }
 
+/// Publishes the values the current thread retired into the default collector which are
+/// still waiting for their batch to fill up.
+#[inline]
+pub fn flush() {
+    COLLECTOR.flush();
+}
+
 /// Returns the default global collector.
 pub fn default_collector() -> &'static Collector {
     &COLLECTOR
@@ -47,7 +57,7 @@ mod tests {
         }
 
         thread_local! {
-            static FOO: Foo = Foo;
+            static FOO: Foo = const { Foo };
         }
 
         let handle = thread::spawn(|| {
@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem::{self, MaybeUninit};
@@ -7,6 +8,7 @@ use core::ptr;
 ///
 /// Three words should be enough for the majority of cases. For example, you can fit inside it the
 /// function pointer together with a fat pointer representing an object that needs to be destroyed.
+/// A retired `Box<[T]>`, `Box<str>` or `Box<dyn Trait>` is therefore stored inline.
 const DATA_WORDS: usize = 3;
 
 /// Some space to keep a `FnOnce()` object on the stack.
@@ -80,8 +82,9 @@ impl Deferred {
 
 #[cfg(all(test, not(loom)))]
 mod tests {
-    use super::Deferred;
+    use super::{Data, Deferred};
     use std::cell::Cell;
+    use std::mem;
 
     #[test]
     fn on_stack() {
@@ -127,6 +130,17 @@ mod tests {
         d.call();
     }
 
+    #[test]
+    fn boxed_trait_object() {
+        let fired = &Cell::new(false);
+        let a: Box<dyn Fn() + '_> = Box::new(move || fired.set(true));
+        assert!(mem::size_of_val(&a) <= mem::size_of::<Data>());
+        let d = Deferred::new(a);
+        assert!(!fired.get());
+        d.call();
+        assert!(fired.get());
+    }
+
     #[test]
     fn long_slice_usize() {
         let a: [usize; 5] = [2, 3, 5, 7, 11];
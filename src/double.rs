@@ -0,0 +1,84 @@
+//! A double-width atomic built on the 128-bit atomics of `portable-atomic`.
+//!
+//! `portable-atomic` uses `cmpxchg16b` where the CPU has it, checked at run time unless the
+//! target feature is enabled, and falls back to a lock otherwise. This builds on stable and
+//! without `std`.
+
+use core::{fmt, marker::PhantomData, mem};
+
+use portable_atomic::{AtomicU128, Ordering};
+
+/// Atomically updated pair of words, stored as their 128-bit representation.
+///
+/// `T` must be exactly 16 bytes without padding. The values are compared and added as
+/// 128-bit integers.
+pub(crate) struct AtomicDouble<T> {
+    v: AtomicU128,
+    marker: PhantomData<T>,
+}
+
+// The value is only ever copied in and out of the atomic.
+unsafe impl<T: Copy + Send> Sync for AtomicDouble<T> {}
+
+impl<T: Copy> AtomicDouble<T> {
+    const SIZE: () = assert!(mem::size_of::<T>() == mem::size_of::<u128>());
+
+    pub(crate) const fn new(v: T) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE;
+        AtomicDouble {
+            v: AtomicU128::new(to_bits(v)),
+            marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn load(&self, order: Ordering) -> T {
+        from_bits(self.v.load(order))
+    }
+
+    pub(crate) fn compare_exchange(
+        &self,
+        current: T,
+        new: T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<T, T> {
+        self.v
+            .compare_exchange(to_bits(current), to_bits(new), success, failure)
+            .map(from_bits)
+            .map_err(from_bits)
+    }
+
+    pub(crate) fn fetch_add(&self, val: T, order: Ordering) -> T {
+        from_bits(self.v.fetch_add(to_bits(val), order))
+    }
+}
+
+/// Reinterprets a value as its bits. Only used once `AtomicDouble::SIZE` checked that both
+/// fields cover the same 16 bytes.
+union Bits<T: Copy> {
+    value: T,
+    bits: u128,
+}
+
+const fn to_bits<T: Copy>(value: T) -> u128 {
+    unsafe { Bits { value }.bits }
+}
+
+const fn from_bits<T: Copy>(bits: u128) -> T {
+    unsafe { Bits { bits }.value }
+}
+
+impl<T: Copy + Default> Default for AtomicDouble<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for AtomicDouble<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AtomicDouble")
+            .field(&self.load(Ordering::SeqCst))
+            .finish()
+    }
+}
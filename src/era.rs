@@ -0,0 +1,366 @@
+//! Birth and access eras used by the robust collectors, and the parts that
+//! [`RobustCollector`](crate::RobustCollector) and [`SingleWidthCollector`](crate::SingleWidthCollector)
+//! share.
+//!
+//! An object is tagged with the global era when it is allocated. A pinned thread
+//! publishes the global era into its slot's access era before it uses a pointer it
+//! loaded, so a slot whose access era is older than every object of a batch cannot
+//! be referencing any of them.
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::batch::{BatchHandle, ProcessBatch, BATCH_SIZE};
+use crate::collector::{adjs, checked_config, Smr, SLOTS_LENGTH};
+use crate::guard::Guard;
+use crate::node::Node;
+use crate::shared::{SharedCell, SharedState};
+use crate::slot::SlotStrategy;
+
+/// Number of births after which the global era is advanced.
+const ERA_FREQ: usize = 128;
+
+#[derive(Debug)]
+pub(crate) struct EraClock {
+    global_era: AtomicUsize,
+    births: AtomicUsize,
+}
+
+impl EraClock {
+    pub(crate) const fn new() -> Self {
+        EraClock {
+            global_era: AtomicUsize::new(0),
+            births: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn birth_era(&self) -> usize {
+        if self.births.fetch_add(1, Ordering::Relaxed) % ERA_FREQ == ERA_FREQ - 1 {
+            self.global_era.fetch_add(1, Ordering::SeqCst);
+        }
+        self.current()
+    }
+
+    pub(crate) fn current(&self) -> usize {
+        self.global_era.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessEra(AtomicUsize);
+
+impl AccessEra {
+    pub(crate) const fn new() -> Self {
+        AccessEra(AtomicUsize::new(0))
+    }
+
+    pub(crate) fn load(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Only to be used by the sole owner of a slot before it reads any pointer.
+    pub(crate) fn reset(&self) {
+        self.0.store(0, Ordering::SeqCst);
+    }
+
+    /// Loads `ptr`, publishing the global era until the two agree.
+    pub(crate) fn protect<T>(
+        &self,
+        clock: &EraClock,
+        ptr: &AtomicPtr<T>,
+        order: Ordering,
+    ) -> *mut T {
+        let mut access_era = self.load();
+        loop {
+            let res = ptr.load(order);
+            let global_era = clock.current();
+            if global_era <= access_era {
+                return res;
+            }
+            access_era = self.touch(global_era);
+        }
+    }
+
+    /// Same as [`AccessEra::protect`] for a value of `ptr` that was already read.
+    pub(crate) fn protect_observed<T>(
+        &self,
+        clock: &EraClock,
+        ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        order: Ordering,
+    ) -> *mut T {
+        let mut access_era = self.load();
+        let mut res = observed;
+        loop {
+            let global_era = clock.current();
+            if global_era <= access_era {
+                return res;
+            }
+            access_era = self.touch(global_era);
+            res = ptr.load(order);
+        }
+    }
+
+    fn touch(&self, era: usize) -> usize {
+        let prev = self.0.fetch_max(era, Ordering::SeqCst);
+        // Pairs with the fence before the access eras are checked while publishing a batch.
+        // Either the retiring thread sees the new era or we do not see the pointer it unlinked.
+        fence(Ordering::SeqCst);
+        prev.max(era)
+    }
+}
+
+/// A slot of an era based collector.
+pub(crate) trait EraSlot: Default + Send + Sync + 'static {
+    fn access_era(&self) -> &AccessEra;
+
+    /// Adds `val` to the list of the slot. Fails if no thread is pinned in the slot.
+    fn add_to_slot(&self, val: &mut Node, adjs: usize) -> Result<(), ()>;
+}
+
+/// The slots of an era based collector, shared with the thread local batches.
+#[derive(Debug)]
+pub(crate) struct EraSlots<S> {
+    slots: Box<[S]>,
+    adjs: usize,
+    batch_size: usize,
+}
+
+impl<S> EraSlots<S> {
+    pub(crate) fn slot(&self, slot: usize) -> &S {
+        &self.slots[slot]
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &S> {
+        self.slots.iter()
+    }
+
+    pub(crate) fn adjs(&self) -> usize {
+        self.adjs
+    }
+}
+
+impl<S: EraSlot> ProcessBatch for EraSlots<S> {
+    fn process_batch_handle(&self, batch_handle: &mut BatchHandle) {
+        let min_birth = batch_handle.get_min_birth();
+        let mut batch_iter = batch_handle.iter(self.slots.len());
+        let mut empty_slots: usize = 0;
+        let node_nref = batch_handle.get_node_nref();
+        fence(Ordering::SeqCst);
+        for slot in self.slots.iter() {
+            if let Some(mut val) = batch_iter.next() {
+                // No thread in this slot has read a pointer since the batch was born.
+                if slot.access_era().load() < min_birth {
+                    empty_slots += 1;
+                    continue;
+                }
+                if slot
+                    .add_to_slot(unsafe { val.as_mut() }, self.adjs)
+                    .is_err()
+                {
+                    empty_slots += 1;
+                }
+            }
+        }
+        if empty_slots > 0 {
+            unsafe {
+                Node::add_to_nref(node_nref, empty_slots.wrapping_mul(self.adjs));
+            };
+        }
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+}
+
+/// What the era based collectors have in common: the configuration, the lazily allocated
+/// slots and the global era, along with retiring and protecting through them.
+#[derive(Debug)]
+pub(crate) struct EraCollector<S: EraSlot> {
+    slots_length: usize,
+    batch_size: usize,
+    strategy: SlotStrategy,
+    slots: SharedCell<EraSlots<S>>,
+    eras: EraClock,
+}
+
+impl<S: EraSlot> EraCollector<S> {
+    pub(crate) const fn new() -> Self {
+        EraCollector::with_config(SLOTS_LENGTH, BATCH_SIZE, SlotStrategy::RoundRobin)
+    }
+
+    pub(crate) const fn with_config(
+        slots_length: usize,
+        batch_size: usize,
+        strategy: SlotStrategy,
+    ) -> Self {
+        EraCollector {
+            slots_length,
+            batch_size,
+            strategy,
+            slots: SharedCell::new(),
+            eras: EraClock::new(),
+        }
+    }
+
+    pub(crate) fn slots_length(&self) -> usize {
+        self.slots_length
+    }
+
+    pub(crate) fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The slot the current thread starts looking from when it pins.
+    pub(crate) fn start_slot(&self) -> usize {
+        self.strategy.slot(self.slots_length)
+    }
+
+    pub(crate) fn eras(&self) -> &EraClock {
+        &self.eras
+    }
+
+    /// Returns the slots of the collector, allocating them on first use.
+    #[inline]
+    pub(crate) fn slots(&self) -> &SharedState<EraSlots<S>> {
+        self.slots.get_or_init(|| EraSlots {
+            slots: (0..self.slots_length).map(|_| S::default()).collect(),
+            adjs: adjs(self.slots_length),
+            batch_size: self.batch_size,
+        })
+    }
+
+    /// Retires `garbage` born in `birth_era` into the slots, for `collector` which owns
+    /// `local_guard`.
+    pub(crate) unsafe fn retire_born<C: Smr + ?Sized, T: ?Sized + Send + 'static>(
+        &self,
+        collector: &C,
+        garbage: Option<NonNull<T>>,
+        birth_era: usize,
+        local_guard: &Guard<'_, C>,
+    ) {
+        if local_guard.is_unprotected() {
+            drop(garbage.map(|garb| Box::from_raw(garb.as_ptr())));
+            return;
+        }
+        local_guard.check_owner(collector);
+        if let Some(garb) = garbage {
+            let mut garb_node = Node::new(Box::from_raw(garb.as_ptr()));
+            garb_node.set_birth_era(birth_era);
+            BatchHandle::add_to_batch(self.slots(), garb_node);
+        }
+    }
+
+    /// Defers `f` for `collector`, see [`Smr::defer_unchecked`].
+    pub(crate) unsafe fn defer_unchecked<C: Smr + ?Sized, F: FnOnce()>(
+        &self,
+        collector: &C,
+        f: F,
+        local_guard: &Guard<'_, C>,
+    ) {
+        if local_guard.is_unprotected() {
+            f();
+            return;
+        }
+        local_guard.check_owner(collector);
+        // Nothing is known about what the closure touches, so it is as old as it gets.
+        BatchHandle::add_to_batch(self.slots(), Node::with_fn(f));
+    }
+
+    pub(crate) fn flush(&self) {
+        BatchHandle::flush(self.slots());
+    }
+
+    /// Loads `ptr` for the thread of `local_guard`, see [`Smr::protect`].
+    pub(crate) fn protect<C: Smr + ?Sized, T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        order: Ordering,
+        local_guard: &Guard<'_, C>,
+    ) -> *mut T {
+        if local_guard.is_unprotected() {
+            return ptr.load(order);
+        }
+        self.slots()
+            .slot(local_guard.slot)
+            .access_era()
+            .protect(&self.eras, ptr, order)
+    }
+
+    /// Protects a value read from `ptr`, see [`Smr::protect_observed`].
+    pub(crate) fn protect_observed<C: Smr + ?Sized, T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        order: Ordering,
+        local_guard: &Guard<'_, C>,
+    ) -> *mut T {
+        if local_guard.is_unprotected() {
+            return observed;
+        }
+        self.slots()
+            .slot(local_guard.slot)
+            .access_era()
+            .protect_observed(&self.eras, ptr, observed, order)
+    }
+}
+
+/// Builds a [`RobustCollector`](crate::RobustCollector) or a
+/// [`SingleWidthCollector`](crate::SingleWidthCollector) with a custom number of slots,
+/// batch size and slot strategy.
+///
+/// ```
+/// use hyaline_smr::SingleWidthCollector;
+///
+/// let collector = SingleWidthCollector::builder().slots(128).batch_size(32).build();
+/// assert_eq!(collector.slots_length(), 128);
+/// assert_eq!(collector.batch_size(), 32);
+/// ```
+#[derive(Debug, Clone)]
+pub struct EraCollectorBuilder<C> {
+    slots: usize,
+    batch_size: Option<usize>,
+    strategy: SlotStrategy,
+    marker: PhantomData<fn() -> C>,
+}
+
+impl<C> EraCollectorBuilder<C> {
+    /// Creates a builder with 64 slots, the configuration of the collector's `new`.
+    pub(crate) fn new() -> Self {
+        EraCollectorBuilder {
+            slots: SLOTS_LENGTH,
+            batch_size: None,
+            strategy: SlotStrategy::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Sets the number of slots. The value is rounded up to the next power of two when the
+    /// collector is built.
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Sets the number of retired values a thread gathers before publishing them to the slots.
+    /// Defaults to the larger of 64 and the number of slots.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// Sets the slot a pinning thread tries first. Defaults to [`SlotStrategy::RoundRobin`].
+    pub fn slot_strategy(mut self, strategy: SlotStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Checks the configuration and creates the shared part of the collector.
+    pub(crate) fn build_era_collector<S: EraSlot>(self) -> EraCollector<S> {
+        let (slots, batch_size) = checked_config(self.slots, self.batch_size);
+        EraCollector::with_config(slots, batch_size, self.strategy)
+    }
+}
@@ -1,39 +1,270 @@
-use std::ptr::NonNull;
+use alloc::boxed::Box;
+use core::mem;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, Ordering};
 
+#[cfg(feature = "std")]
+use crate::affine;
+use crate::atomic::Shared;
+#[cfg(feature = "double-width")]
 use crate::collector::Collector;
 use crate::collector::Smr;
+#[cfg(feature = "double-width")]
+use crate::local::Local;
 use crate::node::Node;
+#[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+use crate::primitive::thread::{self, ThreadId};
 
 /// A RAII guard which keeps the thread active in garbage collection.
 /// The thread will be unpinned automatically upon guard's destruction
 /// # Multiple guards
 ///
-/// Pinning is reentrant and it is perfectly legal to create multiple guards. But it is not advised
-/// to have a gurad while another guard is still active for a thread. This leads to unnecessary
-/// pinning of the thread again.
-/// ```ignore
+/// Pinning is reentrant and it is perfectly legal to create multiple guards. A thread that is
+/// already pinned in a collector is not pinned again, the new guard shares the slot of the
+/// existing one. The thread stays pinned until the last of its guards is dropped.
+#[cfg_attr(feature = "double-width", doc = "```")]
+#[cfg_attr(not(feature = "double-width"), doc = "```ignore")]
 /// use hyaline_smr as hyaline;
 ///
 /// let guard1 = hyaline::pin();
-/// // This kind of usage is not advised as it incurs some performance penalty
+/// // Only bumps a thread local counter.
 /// let guard2 = hyaline::pin();
 ///
-/// hyaline::retire(some_val);
-///
+/// drop(guard1);
+/// // Still pinned by guard2.
 /// ```
+///
+/// `C` is the collector the guard is pinned in, [`Collector`](crate::Collector) by default or
+/// [`SingleWidthCollector`](crate::SingleWidthCollector) without the `double-width` feature.
+// `repr(C)` gives the guards of all the collectors the same layout, see `unprotected`.
 #[derive(Debug)]
-pub struct Guard<'a> {
-    active_collector: &'a Collector,
+#[repr(C)]
+pub struct Guard<'a, C: Smr + ?Sized = DefaultCollector> {
+    /// `None` for the guard returned by [`unprotected`].
+    active_collector: Option<&'a C>,
     pub(crate) handle: Option<&'a Node>,
     pub(crate) slot: usize,
+    /// Set for the guards pinned through a [`LocalHandle`](crate::LocalHandle).
+    #[cfg(feature = "double-width")]
+    pub(crate) local: Option<&'a Local<'a>>,
+    /// The slot the guard was explicitly pinned in and how to pin there again, see
+    /// [`Collector::pin_in_slot`](crate::Collector::pin_in_slot).
+    #[cfg(feature = "double-width")]
+    pub(crate) pinned_in: Option<(usize, PinInSlot<C>)>,
+    /// The thread which created the guard, checked when retiring through it.
+    #[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+    thread: Option<ThreadId>,
 }
 
-impl<'a> Guard<'a> {
-    pub(crate) fn new(coll: &Collector) -> Guard<'_> {
+impl<'a, C: Smr + ?Sized> Guard<'a, C> {
+    pub(crate) fn new(coll: &'a C) -> Guard<'a, C> {
         Guard {
-            active_collector: coll,
+            active_collector: Some(coll),
             handle: None,
             slot: 0,
+            #[cfg(feature = "double-width")]
+            local: None,
+            #[cfg(feature = "double-width")]
+            pinned_in: None,
+            #[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+            thread: Some(thread::current().id()),
+        }
+    }
+
+    #[cfg(feature = "double-width")]
+    pub(crate) fn new_local(coll: &'a C, local: &'a Local<'a>) -> Guard<'a, C> {
+        Guard {
+            active_collector: Some(coll),
+            handle: None,
+            slot: 0,
+            local: Some(local),
+            pinned_in: None,
+            #[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+            thread: Some(thread::current().id()),
+        }
+    }
+
+    /// Returns true for the guard returned by [`unprotected`].
+    #[inline]
+    pub(crate) fn is_unprotected(&self) -> bool {
+        self.active_collector.is_none()
+    }
+
+    /// Panics if the guard was pinned in another collector than `collector`, or on another
+    /// thread. Only checked with debug assertions or the `checked` feature.
+    #[inline]
+    pub(crate) fn check_owner(&self, collector: &C) {
+        #[cfg(any(debug_assertions, feature = "checked"))]
+        {
+            let active_collector = match self.active_collector {
+                Some(active_collector) => active_collector,
+                None => return,
+            };
+            assert!(
+                core::ptr::eq(
+                    active_collector as *const C as *const (),
+                    collector as *const C as *const ()
+                ),
+                "retired with a guard pinned in another collector"
+            );
+            #[cfg(feature = "std")]
+            assert!(
+                self.thread == Some(thread::current().id()),
+                "retired with a guard created on another thread"
+            );
+        }
+        #[cfg(not(any(debug_assertions, feature = "checked")))]
+        let _ = collector;
+    }
+
+    /// Unpins and pins the thread again, so that the garbage retired since the thread was
+    /// pinned can be reclaimed. Pointers loaded through this guard must not be used anymore,
+    /// which is enforced for [`Shared`] by the mutable borrow.
+    ///
+    /// This is cheaper than dropping the guard and pinning again and costs next to nothing
+    /// if no garbage was added to the thread's slot in between.
+    #[inline]
+    pub fn repin(&mut self) {
+        if let Some(collector) = self.active_collector {
+            collector.repin(self);
+        }
+        #[cfg(feature = "std")]
+        affine::drain();
+    }
+
+    /// Unpins the thread while `f` runs and pins it again afterwards, for example around a
+    /// blocking call in a long running read loop.
+    pub fn repin_after<F: FnOnce() -> R, R>(&mut self, f: F) -> R {
+        // Pins again even if `f` panics, as the guard will still unpin on drop.
+        struct PinAgain<'g, 'a, C: Smr + ?Sized>(&'g mut Guard<'a, C>);
+
+        impl<C: Smr + ?Sized> Drop for PinAgain<'_, '_, C> {
+            fn drop(&mut self) {
+                self.0.pin_again();
+            }
+        }
+
+        let collector = match self.active_collector {
+            Some(collector) => collector,
+            None => return f(),
+        };
+        collector.unpin(self);
+        #[cfg(feature = "std")]
+        affine::drain();
+        let _pin_again = PinAgain(self);
+        f()
+    }
+
+    /// Pins the thread again after the guard was unpinned, in the slot it was explicitly
+    /// pinned in or in a fresh one.
+    pub(crate) fn pin_again(&mut self) {
+        #[cfg(feature = "double-width")]
+        if let Some(local) = self.local {
+            let (slot, handle) = local.pin();
+            self.slot = slot;
+            self.handle = handle;
+            return;
+        }
+        let collector = match self.active_collector {
+            Some(collector) => collector,
+            None => return,
+        };
+        #[cfg(feature = "double-width")]
+        let fresh = match self.pinned_in {
+            Some((slot, pin_in_slot)) => pin_in_slot(collector, slot),
+            None => collector.pin(),
+        };
+        #[cfg(not(feature = "double-width"))]
+        let fresh = collector.pin();
+        self.slot = fresh.slot;
+        self.handle = fresh.handle;
+        mem::forget(fresh);
+    }
+
+    /// Loads a pointer that is shared with other threads.
+    ///
+    /// Collectors that are robust against stalled threads need to know which
+    /// objects a thread can reach, so pointers should be read through this method.
+    /// `order` should be at least `Acquire`. For the other collectors this is just a load.
+    #[inline]
+    pub fn protect<T>(&self, ptr: &AtomicPtr<T>, order: Ordering) -> *mut T {
+        match self.active_collector {
+            Some(collector) => collector.protect(ptr, order, self),
+            None => ptr.load(order),
+        }
+    }
+
+    /// Protects a value that was read from `ptr`, see [`Smr::protect_observed`].
+    #[inline]
+    pub(crate) fn protect_observed<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        order: Ordering,
+    ) -> *mut T {
+        match self.active_collector {
+            Some(collector) => collector.protect_observed(ptr, observed, order, self),
+            None => observed,
+        }
+    }
+
+    /// Retires a pointer that was unlinked from a data structure, see [`Smr::retire`].
+    ///
+    /// # Safety
+    /// Same as [`Smr::retire`]: the value must not be reachable from the data structure
+    /// anymore and must not be retired twice.
+    #[inline]
+    pub unsafe fn retire<T: Send + 'static>(&self, ptr: Shared<'_, T>) {
+        match self.active_collector {
+            Some(collector) => collector.retire(ptr.as_non_null(), self),
+            None => drop(ptr.as_non_null().map(|ptr| Box::from_raw(ptr.as_ptr()))),
+        }
+    }
+
+    /// Runs `f` once all the threads that are currently pinned have unpinned.
+    ///
+    /// This can be used to release anything a concurrent reader may still be using, for
+    /// example to return a slab to its pool or to close a file descriptor.
+    #[inline]
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        unsafe { self.defer_unchecked(f) }
+    }
+
+    /// Runs `f` on the current thread once all the threads that are currently pinned have
+    /// unpinned. See [`Smr::defer_local`].
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn defer_local<F: FnOnce() + 'static>(&self, f: F) {
+        match self.active_collector {
+            Some(collector) => collector.defer_local(f, self),
+            None => f(),
+        }
+    }
+
+    /// Same as [`Guard::defer`] without the `Send` and `'static` bounds on `f`.
+    ///
+    /// # Safety
+    /// See [`Smr::defer_unchecked`].
+    #[inline]
+    pub unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F) {
+        match self.active_collector {
+            Some(collector) => collector.defer_unchecked(f, self),
+            None => f(),
+        }
+    }
+
+    /// Publishes the garbage the current thread retired into the collector but which is
+    /// still waiting in its partially filled batch. See [`Smr::flush`]. For a guard pinned
+    /// through a [`LocalHandle`](crate::LocalHandle), this flushes the batch of the handle.
+    #[inline]
+    pub fn flush(&self) {
+        #[cfg(feature = "double-width")]
+        if let Some(local) = self.local {
+            local.flush();
+            return;
+        }
+        if let Some(collector) = self.active_collector {
+            collector.flush();
         }
     }
 
@@ -45,8 +276,77 @@ impl<'a> Guard<'a> {
         first == second
     }
 }
-impl<'a> Drop for Guard<'a> {
+/// Pins a guard of `C` in the given slot.
+#[cfg(feature = "double-width")]
+pub(crate) type PinInSlot<C> = for<'b> fn(&'b C, usize) -> Guard<'b, C>;
+
+#[cfg(feature = "double-width")]
+type DefaultCollector = Collector;
+#[cfg(not(feature = "double-width"))]
+type DefaultCollector = crate::SingleWidthCollector;
+
+/// Stands in for the collector of the unprotected guard, which never calls into it.
+#[derive(Debug)]
+struct NoCollector;
+
+impl Smr for NoCollector {
+    fn pin(&self) -> Guard<'_, Self> {
+        Guard::new(self)
+    }
+
+    fn unpin(&self, _local_guard: &Guard<'_, Self>) {}
+
+    unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F, _local_guard: &Guard<'_, Self>) {
+        f();
+    }
+}
+
+struct UnprotectedGuard(Guard<'static, NoCollector>);
+
+// The unprotected guard is never pinned and only runs the retired values right away.
+unsafe impl Sync for UnprotectedGuard {}
+
+static UNPROTECTED: UnprotectedGuard = UnprotectedGuard(Guard {
+    active_collector: None,
+    handle: None,
+    slot: 0,
+    #[cfg(feature = "double-width")]
+    local: None,
+    #[cfg(feature = "double-width")]
+    pinned_in: None,
+    #[cfg(all(feature = "std", any(debug_assertions, feature = "checked")))]
+    thread: None,
+});
+
+/// Returns a guard which does not pin the thread, for any collector. Values retired or
+/// deferred with it are destroyed right away instead of going through a collector.
+///
+/// This is meant for data structures which are not shared, for example while they are built
+/// or in their `Drop` implementation, where pinning would be pure overhead.
+///
+/// ```
+/// use hyaline_smr::{unprotected, SingleWidthCollector, Smr};
+///
+/// let collector = SingleWidthCollector::new();
+/// let guard = unsafe { unprotected::<SingleWidthCollector>() };
+/// collector.retire_box(Box::new(1), guard);
+/// ```
+///
+/// # Safety
+/// No other thread may be accessing the data that is loaded or retired with this guard.
+#[inline]
+pub unsafe fn unprotected<C: Smr + 'static>() -> &'static Guard<'static, C> {
+    // The guards of all the collectors have the same layout, and this one never uses its
+    // collector.
+    &*(&UNPROTECTED.0 as *const Guard<'static, NoCollector> as *const Guard<'static, C>)
+}
+
+impl<'a, C: Smr + ?Sized> Drop for Guard<'a, C> {
     fn drop(&mut self) {
-        self.active_collector.unpin(self);
+        if let Some(collector) = self.active_collector {
+            collector.unpin(self);
+        }
+        #[cfg(feature = "std")]
+        affine::drain();
     }
 }
@@ -1,15 +1,18 @@
-use std::ptr::NonNull;
+use core::ptr::NonNull;
+#[cfg(feature = "watchdog")]
+use core::sync::atomic::AtomicUsize;
 
-use atomicdouble::AtomicDouble;
-use atomicdouble::Ordering;
-
-use crate::collector::ADJS;
+use crate::collector::Smr;
 use crate::guard::Guard;
 use crate::node::Node;
+use crate::primitive::sync::atomic::{AtomicDouble, Ordering};
 
 #[derive(Debug)]
 pub(crate) struct HeadNode {
     head: AtomicDouble<NonAtomicHeadNode>,
+    /// Nodes added to this slot, one per batch published while it was occupied.
+    #[cfg(feature = "watchdog")]
+    added: AtomicUsize,
 }
 
 impl HeadNode {
@@ -19,10 +22,12 @@ impl HeadNode {
                 head_ptr,
                 head_count,
             }),
+            #[cfg(feature = "watchdog")]
+            added: AtomicUsize::new(0),
         }
     }
 
-    pub(crate) fn add_to_slot(&self, val: &mut Node) -> Result<(), ()> {
+    pub(crate) fn add_to_slot(&self, val: &mut Node, adjs: usize) -> Result<(), ()> {
         let mut curr_node = self.head.load(Ordering::Acquire);
         loop {
             if curr_node.head_count == 0 {
@@ -46,8 +51,13 @@ impl HeadNode {
             );
             match cxchg_result {
                 Ok(_) => {
+                    #[cfg(feature = "watchdog")]
+                    self.added.fetch_add(1, Ordering::Relaxed);
                     unsafe {
-                        Node::add_to_nref(curr_node.head_ptr, curr_node.head_count + ADJS);
+                        Node::add_to_nref(
+                            curr_node.head_ptr,
+                            curr_node.head_count.wrapping_add(adjs),
+                        );
                     };
                     return Ok(());
                 }
@@ -64,7 +74,27 @@ impl HeadNode {
         }
     }
 
-    pub(crate) fn unpin_slot(&self, local_guard: &Guard<'_>) {
+    /// Returns true if no node was added to the slot since the guard with `handle` pinned it.
+    pub(crate) fn is_head(&self, handle: Option<&Node>) -> bool {
+        self.head.load(Ordering::Acquire).head_ptr == handle.map(NonNull::from)
+    }
+
+    pub(crate) fn is_occupied(&self) -> bool {
+        self.head.load(Ordering::Acquire).head_count != 0
+    }
+
+    /// Number of threads currently pinned in the slot.
+    #[cfg(feature = "stats")]
+    pub(crate) fn pinned(&self) -> usize {
+        self.head.load(Ordering::Relaxed).head_count
+    }
+
+    #[cfg(feature = "watchdog")]
+    pub(crate) fn added(&self) -> usize {
+        self.added.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn unpin_slot<C: Smr + ?Sized>(&self, local_guard: &Guard<'_, C>, adjs: usize) {
         let mut curr_head: NonAtomicHeadNode = self.head.load(Ordering::Acquire);
         loop {
             let mut traverse_node = None;
@@ -88,9 +118,9 @@ impl HeadNode {
                 Ordering::Relaxed,
             ) {
                 Ok(_) => {
-                    if curr_head.head_count == 1 && curr_head.head_ptr != None {
+                    if curr_head.head_count == 1 && curr_head.head_ptr.is_some() {
                         unsafe {
-                            Node::add_to_nref(curr_head.head_ptr, ADJS);
+                            Node::add_to_nref(curr_head.head_ptr, adjs);
                         };
                     }
                     if let Some(act_traverse_node) = traverse_node {
@@ -118,13 +148,13 @@ impl HeadNode {
 
 impl Default for HeadNode {
     fn default() -> Self {
-        Self {
-            head: Default::default(),
-        }
+        HeadNode::new(None, 0)
     }
 }
 
-#[derive(Debug)]
+// Both words are reinterpreted as one 128-bit value by the atomic.
+#[repr(C)]
+#[derive(Debug, Default)]
 struct NonAtomicHeadNode {
     head_ptr: Option<NonNull<Node>>,
     head_count: usize,
@@ -144,15 +174,6 @@ impl Clone for NonAtomicHeadNode {
     }
 }
 
-impl Default for NonAtomicHeadNode {
-    fn default() -> Self {
-        NonAtomicHeadNode {
-            head_ptr: None,
-            head_count: 0,
-        }
-    }
-}
-
 impl NonAtomicHeadNode {
     pub(crate) fn new(ptr: Option<NonNull<Node>>, cnt: usize) -> Self {
         NonAtomicHeadNode {
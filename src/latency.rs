@@ -0,0 +1,178 @@
+//! How long retired values live before they are freed, enabled by the `latency` feature.
+
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Number of buckets. Bucket `i` counts the delays of at most `2^i` nanoseconds that did not
+/// fit into the previous one, the last bucket also counts everything longer.
+const BUCKETS: usize = 48;
+
+/// A lock-free histogram of delays with logarithmic buckets.
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY_BUCKET: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            buckets: [EMPTY_BUCKET; BUCKETS],
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, delay: Duration) {
+        let nanos = u64::try_from(delay.as_nanos()).unwrap_or(u64::MAX);
+        // The bounds are inclusive like the `le` label of Prometheus.
+        let bucket =
+            ((u64::BITS - nanos.saturating_sub(1).leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        let mut buckets = [0; BUCKETS];
+        for (count, bucket) in buckets.iter_mut().zip(&self.buckets) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        LatencyHistogram {
+            buckets,
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+/// A snapshot of the time between retiring values and freeing them, see
+/// [`CollectorStats::latency`](crate::CollectorStats::latency).
+///
+/// The delays are counted in buckets whose bounds are powers of two nanoseconds, so the
+/// percentiles are rounded up to the next power of two.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    sum: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    /// Returns the number of freed values.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the sum of the delays of all the freed values.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum)
+    }
+
+    /// Returns the longest delay.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the delay below which the fraction `quantile` of the values were freed, for
+    /// example `0.99` for the 99th percentile. Returns zero if no value was freed yet.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let count = self.count();
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, bucket) in self.buckets() {
+            seen += bucket;
+            if seen >= rank {
+                return bound.min(self.max());
+            }
+        }
+        self.max()
+    }
+
+    /// Returns the upper bound of every bucket together with the number of delays counted in
+    /// it. The last bucket also counts the delays above its bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (Duration::from_nanos(1 << i), *count))
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: [0; BUCKETS],
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Histogram;
+    use crate::{CollectorBuilder, Smr};
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::new();
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_millis(50));
+        let snapshot = histogram.snapshot();
+
+        assert_eq!(snapshot.count(), 101);
+        assert_eq!(snapshot.max(), Duration::from_millis(50));
+        // The median of 51us, rounded up to the next power of two nanoseconds.
+        assert_eq!(snapshot.percentile(0.5), Duration::from_nanos(65536));
+        assert_eq!(snapshot.percentile(0.99), Duration::from_nanos(131072));
+        assert_eq!(snapshot.percentile(1.0), Duration::from_millis(50));
+        assert_eq!(Histogram::new().snapshot().percentile(0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_bounds_are_inclusive() {
+        let histogram = Histogram::new();
+        for nanos in [0, 1, 2, 3, 4, 1024, 1025] {
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        let counts: Vec<_> = histogram.snapshot().buckets().take(12).collect();
+        assert_eq!(counts[0], (Duration::from_nanos(1), 2));
+        assert_eq!(counts[1], (Duration::from_nanos(2), 1));
+        assert_eq!(counts[2], (Duration::from_nanos(4), 2));
+        assert_eq!(counts[10], (Duration::from_nanos(1024), 1));
+        assert_eq!(counts[11], (Duration::from_nanos(2048), 1));
+    }
+
+    #[test]
+    fn measures_reclamation() {
+        let collector = CollectorBuilder::new().slots(2).batch_size(4).build();
+        {
+            let guard = collector.pin();
+            for i in 0..4 {
+                collector.retire_box(Box::new(i), &guard);
+            }
+            collector.flush();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let latency = collector.stats().latency;
+        assert_eq!(latency.count(), 4);
+        assert!(latency.percentile(0.5) >= Duration::from_millis(10));
+        assert!(latency.max() >= Duration::from_millis(10));
+    }
+}
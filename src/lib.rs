@@ -10,8 +10,12 @@
 //! garbage collector will destruct the removed element when no thread can hold a reference to it
 //! anymore.
 //!
-//! This crate implements the Scalable Multiple-List without support for stalled threads
-//! version of Hyaline. When an element gets removed from a concurrent collection, it is inserted into a global list of garbage and
+//! This crate implements the Scalable Multiple-List version of Hyaline. [`Collector`] has no
+//! support for stalled threads while [`RobustCollector`] implements the robust Hyaline-S variant,
+//! where a stalled thread cannot block the reclamation of newer objects. [`SingleWidthCollector`]
+//! implements Hyaline-1 and Hyaline-1S for targets without a double-width compare and swap.
+//!
+//! When an element gets removed from a concurrent collection, it is inserted into a global list of garbage and
 //! every time a thread accesses a collection, it registers itself to the colletor.
 //! When the thread de-registers from the collector it destructs some garbage that became so old that no thread
 //! can be referencing it anymore.
@@ -41,11 +45,45 @@
 //! # APIs
 //!
 //! For majority of use cases, just use the default garbage collector by invoking [`pin`] and [`retire`]. If you
-//! want to create your own garbage collector, use the [`Collector`] API.
+//! want to create your own garbage collector, use the [`Collector`] API. [`Atomic`], [`Owned`] and
+//! [`Shared`] tie the pointers loaded from a data structure to the [`Guard`] they were loaded with.
+//!
+//! # Features
+//!
+//! The `std` feature is enabled by default. Without it the crate only uses `core` and `alloc`.
+//! Threads cannot be told apart then, so an execution context like a CPU registers a
+//! [`LocalHandle`] with [`Collector::register`] and pins through it instead. Guards pinned
+//! directly on a collector still work, but every one of them pins on its own and the values
+//! retired through them gather in a single batch per collector, which the contexts take turns
+//! to fill. `Smr::retire_local` and `Guard::defer_local` need `std`.
+//!
+//! The `stats` feature adds `Collector::stats`, which counts the retired and reclaimed values
+//! at the cost of a relaxed store into a counter of the thread per retired value, and `PrometheusExporter` to
+//! export the counters in the Prometheus text format. The other collectors are not counted.
+//! The `latency` feature additionally
+//! timestamps every retired value to record how long it lived before it was freed in a
+//! `LatencyHistogram`.
+//!
+//! The `watchdog` feature, which needs `std`, records when every thread pinned in a collector
+//! and which thread it is. `Collector::long_lived_guards` lists the guards held for longer
+//! than a threshold, and `Collector::on_stalled_guard` sets a hook which is called for them
+//! by `Collector::check_stalled_guards`, meant to be polled from a monitoring thread. Pinning
+//! and unpinning then update a record of the thread with a few atomic stores, the first pin
+//! of a thread in a collector takes a lock.
+//!
+//! The `double-width` feature is enabled by default. It provides [`Collector`] and
+//! [`RobustCollector`], whose slots need a double-width compare and swap, along with the
+//! default collector, [`CollectorBuilder`] and [`LocalHandle`]. Without it only
+//! [`SingleWidthCollector`] is available, which needs single word atomics only. `stats` and
+//! `watchdog` enable it.
+//!
+//! With debug assertions or the `checked` feature, retiring panics if the guard was pinned in
+//! another collector or, with `std`, created on another thread.
 //!
 //! # Examples
 //! The following is a completely synthetic example.
-//! ```
+#![cfg_attr(feature = "double-width", doc = "```")]
+#![cfg_attr(not(feature = "double-width"), doc = "```ignore")]
 //! use hyaline_smr as hyaline;
 //! use std::{
 //!     ptr::NonNull,
@@ -103,7 +141,9 @@
     unreachable_pub
 )]
 #![allow(dead_code)]
-#![feature(thread_id_value)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 
 #[cfg(loom)]
 #[allow(unused_imports, dead_code)]
@@ -121,11 +161,110 @@ mod primitive {
             }
 
             pub(crate) use self::fence as compiler_fence;
+
+            pub(crate) use self::double::AtomicDouble;
+
+            mod double {
+                use core::{fmt, mem};
+                use std::sync::OnceLock;
+
+                use loom::sync::atomic::Ordering;
+                use loom::sync::Mutex;
+
+                /// Stands in for the `AtomicDouble` of `double.rs`, which loom cannot model.
+                /// Every operation locks a loom mutex, so the orderings are not checked but all the
+                /// interleavings of the operations are explored.
+                ///
+                /// Loom objects cannot be created in a `const fn`, so the mutex is created on
+                /// first use and the collectors keep their const constructors. A collector in
+                /// a `static` outlives the model and must not be used in a loom test.
+                pub(crate) struct AtomicDouble<T> {
+                    init: T,
+                    v: OnceLock<Mutex<T>>,
+                }
+
+                unsafe impl<T: Copy + Send> Sync for AtomicDouble<T> {}
+
+                impl<T: Copy> AtomicDouble<T> {
+                    pub(crate) const fn new(v: T) -> Self {
+                        AtomicDouble {
+                            init: v,
+                            v: OnceLock::new(),
+                        }
+                    }
+
+                    fn get(&self) -> &Mutex<T> {
+                        self.v.get_or_init(|| Mutex::new(self.init))
+                    }
+
+                    pub(crate) fn load(&self, _order: Ordering) -> T {
+                        *self.get().lock().unwrap()
+                    }
+
+                    pub(crate) fn compare_exchange(
+                        &self,
+                        current: T,
+                        new: T,
+                        _success: Ordering,
+                        _failure: Ordering,
+                    ) -> Result<T, T> {
+                        let mut v = self.get().lock().unwrap();
+                        if to_bits(*v) == to_bits(current) {
+                            *v = new;
+                            Ok(current)
+                        } else {
+                            Err(*v)
+                        }
+                    }
+
+                    pub(crate) fn fetch_add(&self, val: T, _order: Ordering) -> T {
+                        let mut v = self.get().lock().unwrap();
+                        let prev = *v;
+                        let sum = to_bits(prev).wrapping_add(to_bits(val));
+                        *v = unsafe { mem::transmute_copy(&sum) };
+                        prev
+                    }
+                }
+
+                // Compares and adds the values as 128-bit integers like the real one does.
+                fn to_bits<T: Copy>(v: T) -> u128 {
+                    assert_eq!(mem::size_of::<T>(), mem::size_of::<u128>());
+                    unsafe { mem::transmute_copy(&v) }
+                }
+
+                impl<T: Copy + Default> Default for AtomicDouble<T> {
+                    fn default() -> Self {
+                        Self::new(T::default())
+                    }
+                }
+
+                impl<T: Copy + fmt::Debug> fmt::Debug for AtomicDouble<T> {
+                    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        f.debug_tuple("AtomicDouble")
+                            .field(&self.load(Ordering::SeqCst))
+                            .finish()
+                    }
+                }
+            }
         }
         pub(crate) use loom::sync::Arc;
     }
+    pub(crate) use loom::hint;
     pub(crate) use loom::thread;
-    pub(crate) use loom::thread_local;
+
+    /// Forwards to `loom::thread_local!`, which does not accept `const` initializers.
+    macro_rules! loom_thread_local {
+        () => {};
+        ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = const { $init:expr }; $($rest:tt)*) => {
+            loom::thread_local!($(#[$attr])* $vis static $name: $t = $init;);
+            $crate::primitive::thread_local!($($rest)*);
+        };
+        ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+            loom::thread_local!($(#[$attr])* $vis static $name: $t = $init;);
+            $crate::primitive::thread_local!($($rest)*);
+        };
+    }
+    pub(crate) use loom_thread_local as thread_local;
 }
 
 #[cfg(not(loom))]
@@ -165,27 +304,93 @@ mod primitive {
             pub(crate) use core::sync::atomic::AtomicPtr;
             pub(crate) use core::sync::atomic::AtomicUsize;
             pub(crate) use core::sync::atomic::Ordering;
+
+            #[cfg(feature = "double-width")]
+            pub(crate) use crate::double::AtomicDouble;
         }
-        pub(crate) use std::sync::Arc;
+        pub(crate) use alloc::sync::Arc;
     }
 
+    pub(crate) use core::hint;
+
+    #[cfg(feature = "std")]
     pub(crate) use std::thread;
 
+    #[cfg(feature = "std")]
     pub(crate) use std::thread_local;
 }
 
+#[cfg(feature = "std")]
+mod affine;
+
+mod atomic;
+pub use self::atomic::{Atomic, CompareExchangeError, Owned, Pointer, Shared};
+
 mod batch;
 
+#[cfg(feature = "double-width")]
+mod builder;
+#[cfg(feature = "double-width")]
+pub use self::builder::CollectorBuilder;
+
 mod collector;
-pub use self::collector::{Collector, Smr};
+#[cfg(feature = "double-width")]
+pub use self::collector::Collector;
+pub use self::collector::Smr;
 
 mod deferred;
+#[cfg(all(feature = "double-width", not(loom)))]
+mod double;
+mod era;
+pub use self::era::EraCollectorBuilder;
 
 mod guard;
-pub use self::guard::Guard;
+pub use self::guard::{unprotected, Guard};
 
+#[cfg(feature = "double-width")]
 mod headnode;
+mod local;
+#[cfg(feature = "double-width")]
+pub use self::local::LocalHandle;
 mod node;
 
+#[cfg(feature = "double-width")]
+mod robust;
+#[cfg(feature = "double-width")]
+pub use self::robust::RobustCollector;
+
+mod shared;
+
+mod single;
+pub use self::single::SingleWidthCollector;
+
+mod slot;
+pub use self::slot::SlotStrategy;
+
+#[cfg(all(test, not(loom)))]
+mod test_util;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use self::stats::CollectorStats;
+
+#[cfg(feature = "latency")]
+mod latency;
+#[cfg(feature = "latency")]
+pub use self::latency::LatencyHistogram;
+
+#[cfg(feature = "stats")]
+mod prometheus;
+#[cfg(feature = "stats")]
+pub use self::prometheus::PrometheusExporter;
+
+#[cfg(feature = "watchdog")]
+mod watchdog;
+#[cfg(feature = "watchdog")]
+pub use self::watchdog::StalledGuard;
+
+#[cfg(feature = "double-width")]
 mod default;
-pub use self::default::{default_collector, pin, retire};
+#[cfg(feature = "double-width")]
+pub use self::default::{default_collector, flush, pin, retire};
@@ -0,0 +1,399 @@
+//! The pins a thread currently holds, one per collector.
+//!
+//! Only the first guard of a thread actually pins it in the collector. Nested guards share
+//! its slot and only bump the depth, and the last guard to be dropped unpins the thread.
+//!
+//! A [`LocalHandle`] keeps the same state for an execution context chosen by the caller,
+//! which also works without thread local storage.
+
+#[cfg(feature = "watchdog")]
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+#[cfg(feature = "double-width")]
+use core::cell::Cell;
+#[cfg(any(feature = "std", feature = "double-width"))]
+use core::cell::RefCell;
+#[cfg(feature = "double-width")]
+use core::fmt;
+#[cfg(feature = "watchdog")]
+use std::thread;
+
+#[cfg(feature = "double-width")]
+use crate::batch::{BatchHandle, ProcessBatch};
+use crate::collector::Smr;
+#[cfg(feature = "double-width")]
+use crate::collector::{Collector, CollectorState};
+use crate::guard::Guard;
+#[cfg(feature = "double-width")]
+use crate::headnode::HeadNode;
+use crate::node::Node;
+#[cfg(feature = "std")]
+use crate::primitive::thread_local;
+#[cfg(feature = "stats")]
+use crate::stats::PendingCount;
+#[cfg(feature = "watchdog")]
+use crate::watchdog::{PinRecord, Watchdog};
+
+#[cfg(feature = "std")]
+thread_local! {
+    static LOCAL_PINS: RefCell<Vec<LocalPin>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct LocalPin {
+    collector: *const (),
+    depth: usize,
+    slot: usize,
+    handle: Option<&'static Node>,
+}
+
+#[cfg(feature = "std")]
+fn key<C: ?Sized>(collector: &C) -> *const () {
+    collector as *const C as *const ()
+}
+
+/// Returns a guard for `collector`, pinning the thread with `pin_slot` unless the thread is
+/// already pinned in it.
+pub(crate) fn pin<'a, C: Smr + ?Sized>(
+    collector: &'a C,
+    pin_slot: impl FnOnce() -> (usize, Option<&'static Node>),
+) -> Guard<'a, C> {
+    let mut result_guard = Guard::new(collector);
+    let (slot, handle) = pin_local(collector, pin_slot);
+    result_guard.slot = slot;
+    result_guard.handle = handle;
+    result_guard
+}
+
+#[cfg(feature = "std")]
+fn pin_local<C: ?Sized>(
+    collector: &C,
+    pin_slot: impl FnOnce() -> (usize, Option<&'static Node>),
+) -> (usize, Option<&'static Node>) {
+    let key = key(collector);
+    let nested = LOCAL_PINS.try_with(|pins| {
+        let mut pins = pins.borrow_mut();
+        let pin = pins.iter_mut().find(|pin| pin.collector == key)?;
+        pin.depth += 1;
+        Some((pin.slot, pin.handle))
+    });
+    match nested {
+        Ok(Some(pin)) => pin,
+        Ok(None) => {
+            let (slot, handle) = pin_slot();
+            LOCAL_PINS.with(|pins| {
+                pins.borrow_mut().push(LocalPin {
+                    collector: key,
+                    depth: 1,
+                    slot,
+                    handle,
+                })
+            });
+            (slot, handle)
+        }
+        // The thread is exiting, every guard pins on its own.
+        Err(_) => pin_slot(),
+    }
+}
+
+/// Without thread local storage every guard pins on its own.
+#[cfg(not(feature = "std"))]
+fn pin_local<C: ?Sized>(
+    _collector: &C,
+    pin_slot: impl FnOnce() -> (usize, Option<&'static Node>),
+) -> (usize, Option<&'static Node>) {
+    pin_slot()
+}
+
+/// Drops a guard of `collector`. Returns true if it was the last one and the thread has to
+/// be unpinned.
+#[cfg(feature = "std")]
+pub(crate) fn unpin<C: ?Sized>(collector: &C) -> bool {
+    let key = key(collector);
+    LOCAL_PINS
+        .try_with(|pins| {
+            let mut pins = pins.borrow_mut();
+            match pins.iter().position(|pin| pin.collector == key) {
+                Some(index) => {
+                    pins[index].depth -= 1;
+                    if pins[index].depth == 0 {
+                        pins.swap_remove(index);
+                        return true;
+                    }
+                    false
+                }
+                // Pinned while the thread local was not available.
+                None => true,
+            }
+        })
+        .unwrap_or(true)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn unpin<C: ?Sized>(_collector: &C) -> bool {
+    true
+}
+
+#[cfg(feature = "double-width")]
+/// The state of a [`LocalHandle`], borrowed by the guards pinned through it.
+pub(crate) struct Local<'a> {
+    state: &'a CollectorState,
+    head: &'a HeadNode,
+    slot: usize,
+    depth: Cell<usize>,
+    handle: Cell<Option<&'static Node>>,
+    batch: RefCell<BatchHandle>,
+    #[cfg(feature = "watchdog")]
+    watchdog: &'a Watchdog,
+    #[cfg(feature = "watchdog")]
+    record: Arc<PinRecord>,
+    #[cfg(feature = "stats")]
+    pending: &'a PendingCount,
+}
+
+#[cfg(feature = "double-width")]
+impl Local<'_> {
+    pub(crate) fn pin(&self) -> (usize, Option<&'static Node>) {
+        if self.depth.get() == 0 {
+            self.handle.set(self.head.pin_slot());
+            #[cfg(feature = "watchdog")]
+            self.watchdog
+                .pinned(Some(&self.record), self.slot, self.head);
+        }
+        self.depth.set(self.depth.get() + 1);
+        (self.slot, self.handle.get())
+    }
+
+    pub(crate) fn unpin<C: Smr + ?Sized>(&self, local_guard: &Guard<'_, C>) {
+        self.depth.set(self.depth.get() - 1);
+        if self.depth.get() == 0 {
+            self.head.unpin_slot(local_guard, self.state.adjs());
+            self.handle.set(None);
+            #[cfg(feature = "watchdog")]
+            self.watchdog.unpinned(Some(&self.record));
+        }
+    }
+
+    /// The record of this handle in the watchdog, the threads have their own.
+    #[cfg(feature = "watchdog")]
+    pub(crate) fn watchdog_record(&self) -> &PinRecord {
+        &self.record
+    }
+
+    pub(crate) fn add_to_batch(&self, val: Node) {
+        // Published once the batch is released, as freeing garbage may retire again.
+        let filled_handle = {
+            let mut batch = self.batch.borrow_mut();
+            let filled_handle = batch.add(val, self.state.batch_size());
+            #[cfg(feature = "stats")]
+            self.pending.set(batch.len());
+            filled_handle
+        };
+        if let Some(filled_handle) = filled_handle {
+            filled_handle.publish(self.state);
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        let filled_handle = self.batch.borrow_mut().take_filled();
+        #[cfg(feature = "stats")]
+        self.pending.set(0);
+        if let Some(filled_handle) = filled_handle {
+            filled_handle.publish(self.state);
+        }
+    }
+}
+
+#[cfg(feature = "double-width")]
+impl Drop for Local<'_> {
+    fn drop(&mut self) {
+        self.flush();
+        #[cfg(feature = "stats")]
+        self.pending.release();
+    }
+}
+
+#[cfg(feature = "double-width")]
+impl fmt::Debug for Local<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Local")
+            .field("slot", &self.slot)
+            .field("depth", &self.depth.get())
+            .finish()
+    }
+}
+
+#[cfg(feature = "double-width")]
+/// A handle through which one execution context, like a thread or a CPU, uses a [`Collector`].
+///
+/// Guards pinned through the handle keep their state and their retired values in the
+/// handle instead of thread local storage, which makes it the way to use a collector
+/// without the `std` feature. The retired values are published when the batch of the
+/// handle is full, when it is flushed and when the handle is dropped.
+///
+/// ```
+/// use hyaline_smr::Collector;
+///
+/// let collector = Collector::new();
+/// let handle = collector.register();
+/// let guard = handle.pin();
+/// guard.defer(|| println!("unpinned"));
+/// ```
+pub struct LocalHandle<'a> {
+    collector: &'a Collector,
+    local: Local<'a>,
+}
+
+#[cfg(feature = "double-width")]
+// The handle of the pinned node is only used while a guard borrows the local handle.
+unsafe impl Send for LocalHandle<'_> {}
+
+#[cfg(feature = "double-width")]
+impl<'a> LocalHandle<'a> {
+    pub(crate) fn new(collector: &'a Collector, slot: usize) -> Self {
+        let state = collector.state();
+        LocalHandle {
+            collector,
+            local: Local {
+                state,
+                head: state.slot_head(slot),
+                slot,
+                depth: Cell::new(0),
+                handle: Cell::new(None),
+                batch: RefCell::new(BatchHandle::new()),
+                #[cfg(feature = "watchdog")]
+                watchdog: state.watchdog(),
+                #[cfg(feature = "watchdog")]
+                record: state.watchdog().register(thread::current()),
+                #[cfg(feature = "stats")]
+                pending: unsafe { state.stats().acquire_pending().as_ref() },
+            },
+        }
+    }
+
+    /// Pins the context of this handle. Nested guards share the pin of the outer guard.
+    pub fn pin(&self) -> Guard<'_, Collector> {
+        let mut result_guard = Guard::new_local(self.collector, &self.local);
+        let (slot, handle) = self.local.pin();
+        result_guard.slot = slot;
+        result_guard.handle = handle;
+        result_guard
+    }
+
+    /// Publishes the values retired through this handle which are still waiting for the
+    /// batch to fill up.
+    pub fn flush(&self) {
+        self.local.flush();
+    }
+
+    /// Returns the collector this handle is registered with.
+    pub fn collector(&self) -> &'a Collector {
+        self.collector
+    }
+
+    /// Returns the slot the guards of this handle are pinned in.
+    pub fn slot(&self) -> usize {
+        self.local.slot
+    }
+}
+
+#[cfg(feature = "double-width")]
+impl fmt::Debug for LocalHandle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalHandle")
+            .field("local", &self.local)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom), feature = "double-width"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::{Collector, CollectorBuilder, Smr};
+
+    #[test]
+    fn local_handle_count_drop() {
+        let collector = Collector::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let handle = collector.register();
+                let dropped = dropped.clone();
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        let guard = handle.pin();
+                        let dropped = dropped.clone();
+                        guard.defer(move || {
+                            dropped.fetch_add(1, Ordering::Relaxed);
+                        });
+                    }
+                    handle.flush();
+                });
+            }
+        });
+        assert_eq!(dropped.load(Ordering::Relaxed), 400);
+    }
+
+    #[test]
+    fn nested_local_pins() {
+        let collector = CollectorBuilder::new().slots(4).build();
+        let handle = collector.register_in_slot(6);
+        assert_eq!(handle.slot(), 2);
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let outer = handle.pin();
+        let inner = handle.pin();
+        assert_eq!(inner.slot, outer.slot);
+        let counter = dropped.clone();
+        inner.defer(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        inner.flush();
+        drop(inner);
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        drop(outer);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn handle_and_thread_pins_are_separate() {
+        let collector = Collector::with_slots(4);
+        let handle = collector.register();
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let thread_guard = collector.pin();
+        let guard = handle.pin();
+        let counter = dropped.clone();
+        guard.defer(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        guard.flush();
+        drop(guard);
+        // The thread is still pinned through the collector itself.
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        drop(thread_guard);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_handle_publishes_batch() {
+        let collector = Collector::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let handle = collector.register();
+        {
+            let guard = handle.pin();
+            let counter = dropped.clone();
+            guard.defer(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        drop(handle);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,7 +1,10 @@
-use std::{mem, ptr::NonNull};
+use alloc::boxed::Box;
+use core::{mem, ptr::NonNull};
+#[cfg(feature = "latency")]
+use std::time::Instant;
 
 use crate::primitive::sync::atomic::Ordering;
-use crate::{batch::Batch, deferred::Deferred, guard::Guard};
+use crate::{batch::Batch, collector::Smr, deferred::Deferred, guard::Guard};
 
 /*
 This is the type that will be used in local batches and retirement lists.
@@ -15,15 +18,26 @@ pub(crate) struct Node {
     list: Option<NonNull<Node>>,
     batch: Option<Box<Node>>,
     nref_node: Option<NonNull<Batch>>,
+    birth_era: usize,
+    /// When the value was retired, `None` for the filler nodes.
+    #[cfg(feature = "latency")]
+    retired_at: Option<Instant>,
 }
 
 impl Node {
-    pub(crate) fn new<T>(val: Box<T>) -> Self {
+    pub(crate) fn new<T: ?Sized>(val: Box<T>) -> Self {
+        Node::with_fn(move || drop(val))
+    }
+
+    pub(crate) fn with_fn<F: FnOnce()>(f: F) -> Self {
         Node {
-            val: Deferred::new(move || drop(val)),
+            val: Deferred::new(f),
             list: None,
             batch: None,
             nref_node: None,
+            birth_era: 0,
+            #[cfg(feature = "latency")]
+            retired_at: Some(Instant::now()),
         }
     }
 
@@ -39,6 +53,14 @@ impl Node {
         self.batch = batch;
     }
 
+    pub(crate) fn get_birth_era(&self) -> usize {
+        self.birth_era
+    }
+
+    pub(crate) fn set_birth_era(&mut self, birth_era: usize) {
+        self.birth_era = birth_era;
+    }
+
     pub(crate) fn set_nref_node(&mut self, nref_node: Option<NonNull<Batch>>) {
         self.nref_node = nref_node;
     }
@@ -47,6 +69,17 @@ impl Node {
         self.batch.is_some()
     }
 
+    /// Returns the next node of the same batch.
+    #[cfg(feature = "latency")]
+    pub(crate) fn next_in_batch(&self) -> Option<&Node> {
+        self.batch.as_deref()
+    }
+
+    #[cfg(feature = "latency")]
+    pub(crate) fn retired_at(&self) -> Option<Instant> {
+        self.retired_at
+    }
+
     //unsafe because its up to the caller to make sure the nref_node is valid
     pub(crate) unsafe fn fetch_add_nref(&self, val: usize, ordering: Ordering) -> usize {
         self.nref_node
@@ -55,7 +88,7 @@ impl Node {
             .fetch_add_nref(val, ordering)
     }
 
-    pub(crate) unsafe fn traverse(&self, local_guard: &Guard<'_>) {
+    pub(crate) unsafe fn traverse<C: Smr + ?Sized>(&self, local_guard: &Guard<'_, C>) {
         let mut current = Some(NonNull::from(self));
         loop {
             let current_ref = current.unwrap().as_ref();
@@ -112,6 +145,9 @@ impl Default for Node {
             list: None,
             batch: None,
             nref_node: None,
+            birth_era: 0,
+            #[cfg(feature = "latency")]
+            retired_at: None,
         }
     }
 }
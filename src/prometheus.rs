@@ -0,0 +1,323 @@
+//! Renders the [`CollectorStats`] of several collectors in the Prometheus text exposition format.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::collector::Collector;
+use crate::stats::CollectorStats;
+
+type StatsSource<'a> = Box<dyn Fn() -> CollectorStats + Send + Sync + 'a>;
+
+type Counter = (&'static str, &'static str, fn(&CollectorStats) -> usize);
+
+/// The counters of a collector with their help text.
+const COUNTERS: [Counter; 4] = [
+    (
+        "hyaline_retired_total",
+        "Values retired into the collector.",
+        |stats| stats.retired,
+    ),
+    (
+        "hyaline_reclaimed_total",
+        "Retired values that were freed.",
+        |stats| stats.reclaimed,
+    ),
+    (
+        "hyaline_batches_published_total",
+        "Batches published into the slots.",
+        |stats| stats.batches_published,
+    ),
+    (
+        "hyaline_batches_freed_total",
+        "Published batches that were freed.",
+        |stats| stats.batches_freed,
+    ),
+];
+
+/// Exports the counters of named collectors in the [Prometheus text format][format], without
+/// depending on an HTTP server. Requires the `stats` feature.
+///
+/// Every series carries a `collector` label with the name the collector was registered with,
+/// the pinned threads also carry a `slot` label.
+///
+/// Only [`Collector`]s can be registered. [`RobustCollector`](crate::RobustCollector) and
+/// [`SingleWidthCollector`](crate::SingleWidthCollector) do not keep any counters.
+///
+/// ```
+/// use hyaline_smr::{Collector, PrometheusExporter};
+///
+/// let cache = Collector::new();
+/// let mut exporter = PrometheusExporter::new();
+/// exporter.register("default", hyaline_smr::default_collector());
+/// exporter.register("cache", &cache);
+///
+/// let text = exporter.render();
+/// assert!(text.contains("hyaline_retired_total{collector=\"cache\"} 0\n"));
+/// ```
+///
+/// [format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+pub struct PrometheusExporter<'a> {
+    collectors: Vec<(Cow<'a, str>, StatsSource<'a>)>,
+}
+
+impl<'a> PrometheusExporter<'a> {
+    /// Creates an exporter without any collector.
+    pub fn new() -> Self {
+        PrometheusExporter {
+            collectors: Vec::new(),
+        }
+    }
+
+    /// Adds `collector` to the exported collectors under `name`. See the type documentation
+    /// for the collectors that can be exported.
+    pub fn register(
+        &mut self,
+        name: impl Into<Cow<'a, str>>,
+        collector: &'a Collector,
+    ) -> &mut Self {
+        self.collectors
+            .push((name.into(), Box::new(move || collector.stats())));
+        self
+    }
+
+    /// Renders the current counters of all the registered collectors.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing into a `String` cannot fail.
+        let _ = self.write_to(&mut out);
+        out
+    }
+
+    /// Writes the current counters of all the registered collectors into `out`.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> fmt::Result {
+        let snapshots: Vec<(&str, CollectorStats)> = self
+            .collectors
+            .iter()
+            .map(|(name, stats)| (name.as_ref(), stats()))
+            .collect();
+
+        for (metric, help, value) in COUNTERS {
+            write_header(out, metric, help, "counter")?;
+            for (name, stats) in &snapshots {
+                write_series(out, metric, name, None, &value(stats))?;
+            }
+        }
+
+        write_header(
+            out,
+            "hyaline_pending",
+            "Retired values waiting in the batches of the threads.",
+            "gauge",
+        )?;
+        for (name, stats) in &snapshots {
+            write_series(out, "hyaline_pending", name, None, &stats.pending)?;
+        }
+
+        write_header(
+            out,
+            "hyaline_pinned_threads",
+            "Threads pinned in a slot.",
+            "gauge",
+        )?;
+        for (name, stats) in &snapshots {
+            for (slot, pinned) in stats.pinned.iter().enumerate() {
+                write_series(
+                    out,
+                    "hyaline_pinned_threads",
+                    name,
+                    Some(("slot", &slot)),
+                    pinned,
+                )?;
+            }
+        }
+
+        #[cfg(feature = "latency")]
+        write_latency(out, &snapshots)?;
+        Ok(())
+    }
+}
+
+impl Default for PrometheusExporter<'_> {
+    fn default() -> Self {
+        PrometheusExporter::new()
+    }
+}
+
+impl fmt::Debug for PrometheusExporter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.collectors.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+fn write_header<W: Write>(out: &mut W, metric: &str, help: &str, kind: &str) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", metric, help)?;
+    writeln!(out, "# TYPE {} {}", metric, kind)
+}
+
+/// Writes a series with the `collector` label and another optional label.
+fn write_series<W: Write>(
+    out: &mut W,
+    metric: &str,
+    collector: &str,
+    label: Option<(&str, &dyn fmt::Display)>,
+    value: &dyn fmt::Display,
+) -> fmt::Result {
+    write!(out, "{}{{collector=\"", metric)?;
+    write_escaped(out, collector)?;
+    out.write_char('"')?;
+    if let Some((label, label_value)) = label {
+        write!(out, ",{}=\"{}\"", label, label_value)?;
+    }
+    writeln!(out, "}} {}", value)
+}
+
+/// Writes the reclamation delays as a histogram in seconds, with the buckets from a
+/// microsecond to about a minute.
+#[cfg(feature = "latency")]
+fn write_latency<W: Write>(out: &mut W, snapshots: &[(&str, CollectorStats)]) -> fmt::Result {
+    write_header(
+        out,
+        "hyaline_reclamation_latency_seconds",
+        "Time between retiring values and freeing them.",
+        "histogram",
+    )?;
+    for (name, stats) in snapshots {
+        let latency = &stats.latency;
+        let mut cumulative = 0;
+        for (i, (bound, count)) in latency.buckets().enumerate() {
+            cumulative += count;
+            if (10..=36).contains(&i) {
+                let bound = bound.as_secs_f64();
+                write_series(
+                    out,
+                    "hyaline_reclamation_latency_seconds_bucket",
+                    name,
+                    Some(("le", &bound)),
+                    &cumulative,
+                )?;
+            }
+        }
+        write_series(
+            out,
+            "hyaline_reclamation_latency_seconds_bucket",
+            name,
+            Some(("le", &"+Inf")),
+            &latency.count(),
+        )?;
+        write_series(
+            out,
+            "hyaline_reclamation_latency_seconds_sum",
+            name,
+            None,
+            &latency.sum().as_secs_f64(),
+        )?;
+        write_series(
+            out,
+            "hyaline_reclamation_latency_seconds_count",
+            name,
+            None,
+            &latency.count(),
+        )?;
+    }
+
+    write_header(
+        out,
+        "hyaline_reclamation_latency_max_seconds",
+        "Longest time between retiring a value and freeing it.",
+        "gauge",
+    )?;
+    for (name, stats) in snapshots {
+        write_series(
+            out,
+            "hyaline_reclamation_latency_max_seconds",
+            name,
+            None,
+            &stats.latency.max().as_secs_f64(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Escapes a label value as required by the text format.
+fn write_escaped<W: Write>(out: &mut W, value: &str) -> fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use crate::{CollectorBuilder, PrometheusExporter, Smr};
+
+    #[test]
+    fn render_collectors() {
+        let first = CollectorBuilder::new().slots(2).batch_size(2).build();
+        let second = CollectorBuilder::new().slots(2).build();
+        let guard = first.pin();
+        for i in 0..3 {
+            first.retire_box(Box::new(i), &guard);
+        }
+
+        let mut exporter = PrometheusExporter::new();
+        exporter
+            .register("first", &first)
+            .register(String::from("sec\"ond"), &second);
+        let text = exporter.render();
+
+        assert!(text.contains(
+            "# TYPE hyaline_retired_total counter\n\
+             hyaline_retired_total{collector=\"first\"} 3\n\
+             hyaline_retired_total{collector=\"sec\\\"ond\"} 0\n"
+        ));
+        assert!(text.contains("hyaline_batches_published_total{collector=\"first\"} 1\n"));
+        assert!(text.contains("hyaline_pending{collector=\"first\"} 1\n"));
+        assert!(text.contains(&format!(
+            "hyaline_pinned_threads{{collector=\"first\",slot=\"{}\"}} 1\n",
+            guard.slot
+        )));
+        assert_eq!(
+            text.matches("# TYPE hyaline_pinned_threads gauge").count(),
+            1
+        );
+        assert_eq!(text.matches("hyaline_pinned_threads{").count(), 4);
+    }
+
+    #[cfg(feature = "latency")]
+    #[test]
+    fn render_latency() {
+        let collector = CollectorBuilder::new().slots(2).batch_size(2).build();
+        {
+            let guard = collector.pin();
+            for i in 0..2 {
+                collector.retire_box(Box::new(i), &guard);
+            }
+            collector.flush();
+        }
+
+        let mut exporter = PrometheusExporter::new();
+        exporter.register("latency", &collector);
+        let text = exporter.render();
+
+        assert!(text.contains("# TYPE hyaline_reclamation_latency_seconds histogram\n"));
+        assert!(text.contains(
+            "hyaline_reclamation_latency_seconds_bucket{collector=\"latency\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(
+            text.contains("hyaline_reclamation_latency_seconds_count{collector=\"latency\"} 2\n")
+        );
+        assert!(text.contains(
+            "hyaline_reclamation_latency_seconds_bucket{collector=\"latency\",le=\"0.000001024\"}"
+        ));
+    }
+}
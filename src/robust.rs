@@ -0,0 +1,343 @@
+//! The robust (Hyaline-S) variant of the collector.
+//!
+//! Every retired object carries the era in which it was allocated and every slot
+//! publishes the latest era its threads have read pointers in. A batch is only
+//! inserted into the slots whose access era is not older than the oldest object in
+//! the batch, so a thread which stalls while pinned only holds back the garbage that
+//! it could actually have seen.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::collector::Smr;
+use crate::era::{AccessEra, EraCollector, EraCollectorBuilder, EraSlot};
+use crate::guard::Guard;
+use crate::headnode::HeadNode;
+use crate::local;
+use crate::node::Node;
+
+/// A pinned slot whose access era is this many eras behind the global era is
+/// considered to be held by a stalled thread and is avoided by new pins.
+const STALL_ERAS: usize = 2;
+
+#[derive(Debug)]
+struct RobustSlot {
+    head: HeadNode,
+    access_era: AccessEra,
+}
+
+impl RobustSlot {
+    fn is_stalled(&self, global_era: usize) -> bool {
+        self.head.is_occupied() && global_era.wrapping_sub(self.access_era.load()) > STALL_ERAS
+    }
+}
+
+impl Default for RobustSlot {
+    fn default() -> Self {
+        RobustSlot {
+            head: HeadNode::new(None, 0),
+            access_era: AccessEra::new(),
+        }
+    }
+}
+
+impl EraSlot for RobustSlot {
+    fn access_era(&self) -> &AccessEra {
+        &self.access_era
+    }
+
+    fn add_to_slot(&self, val: &mut Node, adjs: usize) -> Result<(), ()> {
+        self.head.add_to_slot(val, adjs)
+    }
+}
+
+/// Garbage collector that implements the robust Hyaline-S algorithm.
+///
+/// Unlike [`Collector`](crate::Collector), a thread that is descheduled while holding a [`Guard`] does not
+/// block the reclamation of objects allocated after it stopped reading. To get this
+/// guarantee the objects have to be tagged with [`birth_era`](RobustCollector::birth_era)
+/// when they are allocated, retired with [`retire_born`](RobustCollector::retire_born)
+/// and shared pointers have to be read through [`Guard::protect`].
+/// Objects retired through [`Smr::retire`] and deferred closures are treated as the oldest
+/// possible objects.
+///
+/// Pinning threads skip the slots of stalled threads. Use [`RobustCollector::builder`] to
+/// configure the number of slots, the batch size and the [`SlotStrategy`](crate::SlotStrategy).
+#[derive(Debug)]
+pub struct RobustCollector {
+    inner: EraCollector<RobustSlot>,
+}
+
+impl RobustCollector {
+    /// Creates a new robust collector with 64 slots.
+    ///
+    /// Same as [`Collector::new`](crate::Collector::new), the collector can be kept in a `static`
+    /// or owned and may be moved freely.
+    pub const fn new() -> Self {
+        RobustCollector {
+            inner: EraCollector::new(),
+        }
+    }
+
+    /// Returns a builder to configure the number of slots and the batch size of a collector.
+    ///
+    /// ```
+    /// use hyaline_smr::RobustCollector;
+    ///
+    /// let collector = RobustCollector::builder().slots(8).build();
+    /// assert_eq!(collector.slots_length(), 8);
+    /// ```
+    pub fn builder() -> EraCollectorBuilder<RobustCollector> {
+        EraCollectorBuilder::new()
+    }
+
+    /// Returns the number of slots of this collector.
+    pub fn slots_length(&self) -> usize {
+        self.inner.slots_length()
+    }
+
+    /// Returns the number of retired values a thread gathers before publishing them.
+    pub fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    /// Returns the era to be recorded for an object that is about to be allocated.
+    /// The era has to be taken before the object becomes reachable by other threads and
+    /// handed back to [`retire_born`](RobustCollector::retire_born) when it is retired.
+    pub fn birth_era(&self) -> usize {
+        self.inner.eras().birth_era()
+    }
+
+    /// Collects a garbage value along with the era it was born in.
+    ///
+    /// # Safety
+    /// Same as [`Smr::retire`]. Additionally `birth_era` must be a value returned by
+    /// [`birth_era`](RobustCollector::birth_era) of this collector before `garbage` was
+    /// made reachable by other threads.
+    pub unsafe fn retire_born<T: ?Sized + Send + 'static>(
+        &self,
+        garbage: Option<NonNull<T>>,
+        birth_era: usize,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        self.inner
+            .retire_born(self, garbage, birth_era, local_guard);
+    }
+
+    fn get_slot(&self) -> usize {
+        let slots = self.inner.slots();
+        let slots_length = self.slots_length();
+        let start = self.inner.start_slot();
+        let global_era = self.inner.eras().current();
+        (0..slots_length)
+            .map(|i| (start + i) % slots_length)
+            .find(|&i| !slots.slot(i).is_stalled(global_era))
+            .unwrap_or(start)
+    }
+}
+
+impl Default for RobustCollector {
+    fn default() -> Self {
+        RobustCollector::new()
+    }
+}
+
+impl EraCollectorBuilder<RobustCollector> {
+    /// Builds the collector.
+    ///
+    /// # Panics
+    /// Panics if the batch size is zero, or if the number of slots rounded up to the next
+    /// power of two does not fit into a `usize`.
+    pub fn build(self) -> RobustCollector {
+        RobustCollector {
+            inner: self.build_era_collector(),
+        }
+    }
+}
+
+impl Smr for RobustCollector {
+    fn pin(&self) -> Guard<'_, Self> {
+        local::pin(self, || {
+            let slot = self.get_slot();
+            (slot, self.inner.slots().slot(slot).head.pin_slot())
+        })
+    }
+
+    fn unpin(&self, local_guard: &Guard<'_, Self>) {
+        if local::unpin(self) {
+            let slots = self.inner.slots();
+            slots
+                .slot(local_guard.slot)
+                .head
+                .unpin_slot(local_guard, slots.adjs());
+        }
+    }
+
+    fn repin<'a>(&'a self, local_guard: &mut Guard<'a, Self>) {
+        if self
+            .inner
+            .slots()
+            .slot(local_guard.slot)
+            .head
+            .is_head(local_guard.handle)
+        {
+            return;
+        }
+        self.unpin(local_guard);
+        local_guard.pin_again();
+    }
+
+    unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F, local_guard: &Guard<'_, Self>) {
+        self.inner.defer_unchecked(self, f, local_guard);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+
+    fn protect<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        order: Ordering,
+        local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        self.inner.protect(ptr, order, local_guard)
+    }
+
+    fn protect_observed<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        order: Ordering,
+        local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        self.inner
+            .protect_observed(ptr, observed, order, local_guard)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        ptr::NonNull,
+        sync::atomic::{AtomicPtr, Ordering},
+        thread,
+    };
+
+    use crate::test_util::DropCounter;
+    use crate::{RobustCollector, Smr};
+
+    const MAX_THREADS: usize = 8;
+
+    #[test]
+    fn count_drop() {
+        static COLLECTOR: RobustCollector = RobustCollector::new();
+        let drops = DropCounter::new();
+        let mut handle_array = Vec::new();
+
+        for _i in 0..MAX_THREADS {
+            let drops = drops.clone();
+            let handle = thread::spawn(move || {
+                let guard = COLLECTOR.pin();
+                for _j in 0..5000 {
+                    unsafe {
+                        let era = COLLECTOR.birth_era();
+                        COLLECTOR.retire_born(drops.boxed(), era, &guard);
+                    }
+                }
+            });
+            handle_array.push(handle);
+        }
+        for handle in handle_array {
+            handle.join().unwrap();
+        }
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        COLLECTOR.flush();
+        assert_eq!(drops.count(), MAX_THREADS * 5000);
+    }
+
+    #[test]
+    fn stalled_guard() {
+        static COLLECTOR: RobustCollector = RobustCollector::new();
+        let drops = DropCounter::new();
+
+        // This guard never reads anything newer than era 0, like a thread that got
+        // descheduled right after pinning.
+        let stalled = COLLECTOR.pin();
+        let thread_drops = drops.clone();
+        thread::spawn(move || {
+            for _i in 0..1000 {
+                COLLECTOR.birth_era();
+            }
+            let guard = COLLECTOR.pin();
+            for _j in 0..500 {
+                unsafe {
+                    let era = COLLECTOR.birth_era();
+                    COLLECTOR.retire_born(thread_drops.boxed(), era, &guard);
+                }
+            }
+        })
+        .join()
+        .unwrap();
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        COLLECTOR.flush();
+        assert_eq!(drops.count(), 500);
+        drop(stalled);
+    }
+
+    #[test]
+    fn builder() {
+        let drops = DropCounter::new();
+
+        let collector = RobustCollector::builder().slots(3).batch_size(8).build();
+        assert_eq!(collector.slots_length(), 4);
+        assert_eq!(collector.batch_size(), 8);
+        thread::scope(|s| {
+            for _i in 0..MAX_THREADS {
+                s.spawn(|| {
+                    let guard = collector.pin();
+                    assert!(guard.slot < 4);
+                    for _j in 0..100 {
+                        unsafe {
+                            let era = collector.birth_era();
+                            collector.retire_born(drops.boxed(), era, &guard);
+                        }
+                    }
+                    // The thread local batch may only be published after the scope ends.
+                    drop(guard);
+                    collector.flush();
+                });
+            }
+        });
+        assert_eq!(drops.count(), MAX_THREADS * 100);
+    }
+
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    #[test]
+    #[should_panic(expected = "retired with a guard pinned in another collector")]
+    fn retire_born_with_guard_of_other_collector() {
+        let first = RobustCollector::new();
+        let second = RobustCollector::new();
+        let guard = first.pin();
+        unsafe {
+            second.retire_born(NonNull::new(Box::into_raw(Box::new(1))), 0, &guard);
+        }
+    }
+
+    #[test]
+    fn unprotected() {
+        let drops = DropCounter::new();
+
+        let collector = RobustCollector::new();
+        let guard = unsafe { crate::unprotected::<RobustCollector>() };
+        let value = AtomicPtr::new(drops.boxed().unwrap().as_ptr());
+        let loaded = guard.protect(&value, Ordering::Acquire);
+        unsafe {
+            collector.retire_born(NonNull::new(loaded), collector.birth_era(), guard);
+            collector.retire(drops.boxed(), guard);
+        }
+        assert_eq!(drops.count(), 2);
+    }
+}
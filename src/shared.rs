@@ -0,0 +1,145 @@
+//! The part of a collector that the thread local batches publish into.
+//!
+//! A collector lazily allocates its slots in a [`SharedState`] the first time it is used, so
+//! that collectors can still be created in a `const fn`. The thread local batches hold on
+//! to the allocation instead of pointing at the collector, which may be moved while they
+//! wait, and find their collector by its address, which cannot be reused as long as they
+//! hold it. When the collector is dropped, no thread can be pinned in its slots anymore,
+//! so the batches published into them afterwards are freed right away.
+//!
+//! Without `std` the threads retire into a batch kept in the [`SharedState`], which the
+//! collector publishes when it is dropped.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::batch::ProcessBatch;
+#[cfg(not(feature = "std"))]
+use crate::batch::{BatchHandle, SharedBatch};
+
+pub(crate) struct SharedState<T: ?Sized> {
+    alive: AtomicBool,
+    /// The batch the threads retire into, as they have no thread local one.
+    #[cfg(not(feature = "std"))]
+    batch: SharedBatch,
+    state: T,
+}
+
+impl<T: ?Sized> SharedState<T> {
+    /// Returns false once the collector owning the state is dropped.
+    pub(crate) fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Acquire)
+    }
+
+    /// Identifies the collector owning the state.
+    pub(crate) fn key(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn batch(&self) -> &SharedBatch {
+        &self.batch
+    }
+}
+
+impl<T> SharedState<T> {
+    /// Returns another reference to the allocation. Every `SharedState` is allocated by a
+    /// [`SharedCell`], which keeps one count until it is dropped.
+    pub(crate) fn to_arc(&self) -> Arc<SharedState<T>> {
+        unsafe {
+            Arc::increment_strong_count(self);
+            Arc::from_raw(self)
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SharedState<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.state
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SharedState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.state.fmt(f)
+    }
+}
+
+/// The collector's side of a [`SharedState`].
+pub(crate) struct SharedCell<T: ProcessBatch + 'static> {
+    ptr: AtomicPtr<SharedState<T>>,
+    marker: PhantomData<Arc<SharedState<T>>>,
+}
+
+impl<T: ProcessBatch + 'static> SharedCell<T> {
+    pub(crate) const fn new() -> Self {
+        SharedCell {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the state, allocating it with `init` on first use.
+    #[inline]
+    pub(crate) fn get_or_init(&self, init: impl FnOnce() -> T) -> &SharedState<T> {
+        let curr = self.ptr.load(Ordering::Acquire);
+        if curr.is_null() {
+            return self.init(init);
+        }
+        // The cell owns one count which is only released in drop.
+        unsafe { &*curr }
+    }
+
+    #[cold]
+    fn init(&self, init: impl FnOnce() -> T) -> &SharedState<T> {
+        let new = Arc::into_raw(Arc::new(SharedState {
+            alive: AtomicBool::new(true),
+            #[cfg(not(feature = "std"))]
+            batch: SharedBatch::new(),
+            state: init(),
+        })) as *mut SharedState<T>;
+        match self
+            .ptr
+            .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => unsafe { &*new },
+            Err(pres) => {
+                drop(unsafe { Arc::from_raw(new) });
+                unsafe { &*pres }
+            }
+        }
+    }
+
+    /// Returns the state if it was allocated.
+    pub(crate) fn get(&self) -> Option<&SharedState<T>> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+}
+
+impl<T: ProcessBatch + fmt::Debug + 'static> fmt::Debug for SharedCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedCell").field(&self.get()).finish()
+    }
+}
+
+impl<T: ProcessBatch + 'static> Drop for SharedCell<T> {
+    fn drop(&mut self) {
+        let curr = *self.ptr.get_mut();
+        if !curr.is_null() {
+            let shared = unsafe { Arc::from_raw(curr) };
+            shared.alive.store(false, Ordering::Release);
+            // Nobody can be pinned anymore, the garbage of the current thread is freed here.
+            // The other threads free theirs once they notice the collector is gone.
+            #[cfg(feature = "std")]
+            crate::batch::release_local(shared.key());
+            #[cfg(not(feature = "std"))]
+            BatchHandle::flush(&shared);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! The single-width (Hyaline-1 and Hyaline-1S) variant of the collector.
+//!
+//! Each pinned thread owns a slot for as long as it is pinned, so the reference
+//! count of a slot is at most one and fits in the low bit of the head pointer.
+//! Only single word atomics are needed, which makes this collector usable on targets
+//! without a double-width compare and swap.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::collector::Smr;
+use crate::era::{AccessEra, EraCollector, EraCollectorBuilder, EraSlot};
+use crate::guard::Guard;
+use crate::local;
+use crate::node::Node;
+#[cfg(feature = "std")]
+use crate::primitive::thread;
+
+/// Low bit of a slot's head, set while a thread owns the slot.
+const ACTIVE: usize = 1;
+
+#[derive(Debug)]
+struct SingleSlot {
+    head: AtomicUsize,
+    access_era: AccessEra,
+}
+
+impl SingleSlot {
+    fn head_ptr(head: usize) -> Option<NonNull<Node>> {
+        NonNull::new((head & !ACTIVE) as *mut Node)
+    }
+
+    fn try_enter(&self) -> bool {
+        let entered = self
+            .head
+            .compare_exchange(0, ACTIVE, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if entered {
+            // The previous owner is gone and we have not read anything yet.
+            self.access_era.reset();
+        }
+        entered
+    }
+
+    fn leave(&self, local_guard: &Guard<'_, SingleWidthCollector>, adjs: usize) {
+        let curr_head = self.head.swap(0, Ordering::AcqRel);
+        if let Some(head_ptr) = SingleSlot::head_ptr(curr_head) {
+            unsafe {
+                // The owner was the only reference, so the whole list is ours to traverse.
+                let traverse_node = head_ptr.as_ref().get_list();
+                Node::add_to_nref(Some(head_ptr), adjs);
+                if let Some(act_traverse_node) = traverse_node {
+                    act_traverse_node.as_ref().traverse(local_guard);
+                }
+            }
+        }
+    }
+}
+
+impl Default for SingleSlot {
+    fn default() -> Self {
+        SingleSlot {
+            head: AtomicUsize::new(0),
+            access_era: AccessEra::new(),
+        }
+    }
+}
+
+impl EraSlot for SingleSlot {
+    fn access_era(&self) -> &AccessEra {
+        &self.access_era
+    }
+
+    fn add_to_slot(&self, val: &mut Node, adjs: usize) -> Result<(), ()> {
+        let mut curr_head = self.head.load(Ordering::Acquire);
+        loop {
+            if curr_head & ACTIVE == 0 {
+                val.set_list(None);
+                return Err(());
+            }
+            val.set_list(SingleSlot::head_ptr(curr_head));
+
+            let new_head = val as *mut Node as usize | ACTIVE;
+            match self.head.compare_exchange(
+                curr_head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    unsafe {
+                        Node::add_to_nref(SingleSlot::head_ptr(curr_head), 1 + adjs);
+                    };
+                    return Ok(());
+                }
+                Err(pres_head) => curr_head = pres_head,
+            }
+        }
+    }
+}
+
+/// Garbage collector that implements the single-width Hyaline-1 and Hyaline-1S algorithms.
+///
+/// Every pinned thread gets a slot of its own, so at most
+/// [`slots_length`](SingleWidthCollector::slots_length) threads can be pinned at the same
+/// time. Nested guards of a thread share its slot. Unlike [`Collector`](crate::Collector),
+/// whose pins never wait, a pin waits until a slot is released when all of them are taken.
+/// It also supports the era based robustness of [`RobustCollector`](crate::RobustCollector)
+/// through [`birth_era`](SingleWidthCollector::birth_era),
+/// [`retire_born`](SingleWidthCollector::retire_born) and [`Guard::protect`].
+///
+/// Use [`SingleWidthCollector::builder`] to configure the number of slots, the batch size
+/// and the [`SlotStrategy`](crate::SlotStrategy) that picks the first slot a thread tries.
+///
+/// # Deadlocks
+/// [`Smr::pin`] spins until a slot is free, so it never returns if the threads holding
+/// all the slots wait for the pinning thread. Without the `std` feature threads cannot be
+/// told apart and every nested guard takes a slot of its own: a thread holding as many
+/// guards as there are slots deadlocks on its next pin. Configure at least as many slots
+/// as guards can be alive at the same time.
+#[derive(Debug)]
+pub struct SingleWidthCollector {
+    inner: EraCollector<SingleSlot>,
+}
+
+impl SingleWidthCollector {
+    /// Creates a new single-width collector.
+    ///
+    /// Same as [`Collector::new`](crate::Collector::new), the collector can be kept in a `static`
+    /// or owned and may be moved freely.
+    pub const fn new() -> Self {
+        SingleWidthCollector {
+            inner: EraCollector::new(),
+        }
+    }
+
+    /// Returns a builder to configure the number of slots and the batch size of a collector.
+    pub fn builder() -> EraCollectorBuilder<SingleWidthCollector> {
+        EraCollectorBuilder::new()
+    }
+
+    /// Returns the number of slots of this collector, which is the number of threads that
+    /// can be pinned at the same time.
+    pub fn slots_length(&self) -> usize {
+        self.inner.slots_length()
+    }
+
+    /// Returns the number of retired values a thread gathers before publishing them.
+    pub fn batch_size(&self) -> usize {
+        self.inner.batch_size()
+    }
+
+    /// Returns the era to be recorded for an object that is about to be allocated.
+    /// See [`RobustCollector::birth_era`](crate::RobustCollector::birth_era).
+    pub fn birth_era(&self) -> usize {
+        self.inner.eras().birth_era()
+    }
+
+    /// Collects a garbage value along with the era it was born in.
+    ///
+    /// # Safety
+    /// Same as [`RobustCollector::retire_born`](crate::RobustCollector::retire_born).
+    pub unsafe fn retire_born<T: ?Sized + Send + 'static>(
+        &self,
+        garbage: Option<NonNull<T>>,
+        birth_era: usize,
+        local_guard: &Guard<'_, Self>,
+    ) {
+        self.inner
+            .retire_born(self, garbage, birth_era, local_guard);
+    }
+
+    /// Waits for a free slot, see the `# Deadlocks` section of [`SingleWidthCollector`].
+    fn enter_slot(&self) -> usize {
+        let slots = self.inner.slots();
+        let slots_length = self.slots_length();
+        let start = self.inner.start_slot();
+        loop {
+            let free_slot = (0..slots_length)
+                .map(|i| (start + i) % slots_length)
+                .find(|&i| slots.slot(i).try_enter());
+            match free_slot {
+                Some(slot) => return slot,
+                #[cfg(feature = "std")]
+                None => thread::yield_now(),
+                #[cfg(not(feature = "std"))]
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+impl Default for SingleWidthCollector {
+    fn default() -> Self {
+        SingleWidthCollector::new()
+    }
+}
+
+impl EraCollectorBuilder<SingleWidthCollector> {
+    /// Builds the collector.
+    ///
+    /// # Panics
+    /// Panics if the batch size is zero, or if the number of slots rounded up to the next
+    /// power of two does not fit into a `usize`.
+    pub fn build(self) -> SingleWidthCollector {
+        SingleWidthCollector {
+            inner: self.build_era_collector(),
+        }
+    }
+}
+
+impl Smr for SingleWidthCollector {
+    fn pin(&self) -> Guard<'_, Self> {
+        local::pin(self, || (self.enter_slot(), None))
+    }
+
+    fn unpin(&self, local_guard: &Guard<'_, Self>) {
+        if local::unpin(self) {
+            let slots = self.inner.slots();
+            slots
+                .slot(local_guard.slot)
+                .leave(local_guard, slots.adjs());
+        }
+    }
+
+    fn repin<'a>(&'a self, local_guard: &mut Guard<'a, Self>) {
+        // Nothing was retired into the slot while we owned it.
+        if self
+            .inner
+            .slots()
+            .slot(local_guard.slot)
+            .head
+            .load(Ordering::Acquire)
+            == ACTIVE
+        {
+            return;
+        }
+        self.unpin(local_guard);
+        local_guard.pin_again();
+    }
+
+    unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F, local_guard: &Guard<'_, Self>) {
+        self.inner.defer_unchecked(self, f, local_guard);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+
+    fn protect<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        order: Ordering,
+        local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        self.inner.protect(ptr, order, local_guard)
+    }
+
+    fn protect_observed<T>(
+        &self,
+        ptr: &AtomicPtr<T>,
+        observed: *mut T,
+        order: Ordering,
+        local_guard: &Guard<'_, Self>,
+    ) -> *mut T {
+        self.inner
+            .protect_observed(ptr, observed, order, local_guard)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        ptr::NonNull,
+        sync::atomic::{AtomicPtr, Ordering},
+        thread,
+    };
+
+    use crate::test_util::{Counted, DropCounter};
+    use crate::{SingleWidthCollector, Smr};
+
+    const MAX_THREADS: usize = 8;
+
+    struct TestNode {
+        _counted: Counted,
+        birth_era: usize,
+    }
+
+    fn node_producer(drops: &DropCounter, birth_era: usize) -> *mut TestNode {
+        Box::into_raw(Box::new(TestNode {
+            _counted: drops.counted(),
+            birth_era,
+        }))
+    }
+
+    #[test]
+    fn count_drop() {
+        static COLLECTOR: SingleWidthCollector = SingleWidthCollector::new();
+        let drops = DropCounter::new();
+        let mut handle_array = Vec::new();
+
+        for _i in 0..MAX_THREADS {
+            let drops = drops.clone();
+            let handle = thread::spawn(move || {
+                let guard = COLLECTOR.pin();
+                for _j in 0..5000 {
+                    unsafe {
+                        COLLECTOR.retire(NonNull::new(node_producer(&drops, 0)), &guard);
+                    }
+                }
+            });
+            handle_array.push(handle);
+        }
+        for handle in handle_array {
+            handle.join().unwrap();
+        }
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        COLLECTOR.flush();
+        assert_eq!(drops.count(), MAX_THREADS * 5000);
+    }
+
+    #[test]
+    fn shared_pointer() {
+        static COLLECTOR: SingleWidthCollector = SingleWidthCollector::new();
+        let drops = DropCounter::new();
+        static SHARED: AtomicPtr<TestNode> = AtomicPtr::new(std::ptr::null_mut());
+        SHARED.store(node_producer(&drops, 0), Ordering::Release);
+
+        let handle_array: Vec<_> = (0..MAX_THREADS)
+            .map(|_| {
+                let drops = drops.clone();
+                thread::spawn(move || {
+                    for _j in 0..1000 {
+                        let guard = COLLECTOR.pin();
+                        let new = node_producer(&drops, COLLECTOR.birth_era());
+                        let old = SHARED.swap(new, Ordering::AcqRel);
+                        let curr = guard.protect(&SHARED, Ordering::Acquire);
+                        unsafe {
+                            assert!((*curr).birth_era <= COLLECTOR.birth_era());
+                            COLLECTOR.retire_born(NonNull::new(old), (*old).birth_era, &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handle_array {
+            handle.join().unwrap();
+        }
+        // Without `std` the threads share a batch, which is only published once full.
+        #[cfg(not(feature = "std"))]
+        COLLECTOR.flush();
+        assert_eq!(drops.count(), MAX_THREADS * 1000);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn nested_pins_share_slot() {
+        static COLLECTOR: SingleWidthCollector = SingleWidthCollector::new();
+        // More guards than slots, which would wait forever if each took its own slot.
+        let guards: Vec<_> = (0..100).map(|_| COLLECTOR.pin()).collect();
+        assert!(guards.iter().all(|guard| guard.slot == guards[0].slot));
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn nested_pins_take_slots() {
+        let collector = SingleWidthCollector::builder().slots(2).build();
+        let first = collector.pin();
+        let second = collector.pin();
+        // A third guard would wait forever for one of these to be dropped.
+        assert_ne!(first.slot, second.slot);
+    }
+
+    #[test]
+    fn builder() {
+        let drops = DropCounter::new();
+
+        let collector = SingleWidthCollector::builder()
+            .slots(3)
+            .batch_size(8)
+            .build();
+        assert_eq!(collector.slots_length(), 4);
+        assert_eq!(collector.batch_size(), 8);
+        // Twice as many threads as slots, so some of them wait for a slot.
+        thread::scope(|s| {
+            for _i in 0..MAX_THREADS {
+                s.spawn(|| {
+                    let guard = collector.pin();
+                    assert!(guard.slot < 4);
+                    for _j in 0..100 {
+                        unsafe {
+                            let era = collector.birth_era();
+                            let node = node_producer(&drops, era);
+                            collector.retire_born(NonNull::new(node), era, &guard);
+                        }
+                    }
+                    // The thread local batch may only be published after the scope ends.
+                    drop(guard);
+                    collector.flush();
+                });
+            }
+        });
+        assert_eq!(drops.count(), MAX_THREADS * 100);
+    }
+
+    #[cfg(any(debug_assertions, feature = "checked"))]
+    #[test]
+    #[should_panic(expected = "retired with a guard pinned in another collector")]
+    fn retire_born_with_guard_of_other_collector() {
+        let first = SingleWidthCollector::new();
+        let second = SingleWidthCollector::new();
+        let guard = first.pin();
+        unsafe {
+            second.retire_born(NonNull::new(Box::into_raw(Box::new(1))), 0, &guard);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Mapping of pinned threads to the slots of a collector.
+
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "std")]
+use std::hash::{Hash, Hasher};
+
+use crate::primitive::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use crate::primitive::thread;
+#[cfg(feature = "std")]
+use crate::primitive::thread_local;
+
+/// Hands out the thread indices, in the order the threads first pin.
+#[cfg(not(loom))]
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+// Starts over in every execution of a loom model, so that the threads get the same slots.
+#[cfg(loom)]
+loom::lazy_static! {
+    static ref NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed);
+
+    static THREAD_HASH: usize = {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        hasher.finish() as usize
+    };
+}
+
+/// Decides which slot a thread is pinned in.
+///
+/// Threads sharing a slot contend on the same head, so the strategy should spread the
+/// threads that are pinned at the same time over different slots. A thread can also pick
+/// its slot itself with [`Collector::pin_in_slot`](crate::Collector::pin_in_slot).
+///
+/// Without the `std` feature threads cannot be told apart, so every strategy hands out
+/// the slots one after another to each pin.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SlotStrategy {
+    /// Hashes the id of the thread.
+    ThreadHash,
+    /// Hands out the slots one after another to threads in the order they first pin.
+    #[default]
+    RoundRobin,
+    /// Uses the CPU the thread is currently running on. Only supported on Linux with the
+    /// `std` feature, other targets fall back to [`SlotStrategy::RoundRobin`].
+    Cpu,
+}
+
+impl SlotStrategy {
+    /// Maps the current thread to one of `slots_length` slots.
+    pub(crate) fn slot(self, slots_length: usize) -> usize {
+        let slot = match self {
+            SlotStrategy::ThreadHash => thread_hash(),
+            SlotStrategy::RoundRobin => thread_index(),
+            SlotStrategy::Cpu => current_cpu().unwrap_or_else(thread_index),
+        };
+        slot % slots_length
+    }
+}
+
+// The thread locals may already be destroyed if a thread pins while it is exiting.
+#[cfg(feature = "std")]
+fn thread_index() -> usize {
+    THREAD_INDEX.try_with(|index| *index).unwrap_or(0)
+}
+
+#[cfg(feature = "std")]
+fn thread_hash() -> usize {
+    THREAD_HASH.try_with(|hash| *hash).unwrap_or(0)
+}
+
+#[cfg(not(feature = "std"))]
+fn thread_index() -> usize {
+    NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(not(feature = "std"))]
+fn thread_hash() -> usize {
+    thread_index()
+}
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn current_cpu() -> Option<usize> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        None
+    } else {
+        Some(cpu as usize)
+    }
+}
+
+#[cfg(not(all(feature = "std", target_os = "linux")))]
+fn current_cpu() -> Option<usize> {
+    None
+}
+
+#[cfg(all(test, not(loom), feature = "double-width"))]
+mod tests {
+    use std::thread;
+
+    use crate::{Collector, SlotStrategy, Smr};
+
+    #[test]
+    fn strategies_stay_in_bounds() {
+        for &strategy in &[
+            SlotStrategy::ThreadHash,
+            SlotStrategy::RoundRobin,
+            SlotStrategy::Cpu,
+        ] {
+            let collector = Collector::builder()
+                .slots(4)
+                .slot_strategy(strategy)
+                .build();
+            assert_eq!(collector.slot_strategy(), strategy);
+            thread::scope(|s| {
+                for _i in 0..8 {
+                    s.spawn(|| {
+                        let guard = collector.pin();
+                        assert!(guard.slot < 4);
+                        // The hash and the index of a thread do not change.
+                        #[cfg(feature = "std")]
+                        if strategy != SlotStrategy::Cpu {
+                            assert_eq!(strategy.slot(4), guard.slot);
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn pin_in_slot() {
+        let collector = Collector::builder().slots(4).build();
+        let guard = collector.pin_in_slot(2);
+        assert_eq!(guard.slot, 2);
+        // Nested guards stay in the slot of the outer guard.
+        #[cfg(feature = "std")]
+        assert_eq!(collector.pin_in_slot(3).slot, 2);
+        drop(guard);
+        let guard = collector.pin_in_slot(7);
+        assert_eq!(guard.slot, 3);
+    }
+
+    #[test]
+    fn repin_stays_in_slot() {
+        let collector = Collector::builder().slots(8).build();
+        // At most one of the slots is the one the strategy would pick.
+        for slot in 0..8 {
+            let mut guard = collector.pin_in_slot(slot);
+            // Publishes a node into the slot, so that repinning has to unpin.
+            guard.defer(|| {});
+            guard.flush();
+            guard.repin();
+            assert_eq!(guard.slot, slot);
+            guard.defer(|| {});
+            guard.flush();
+            guard.repin_after(|| {});
+            assert_eq!(guard.slot, slot);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Counters of what a [`Collector`](crate::Collector) is doing, enabled by the `stats` feature.
+//!
+//! Every thread and every [`LocalHandle`](crate::LocalHandle) counts the values waiting in its
+//! batch in a counter of its own, the other counters are updated once per batch. The retired
+//! values are the published ones plus the waiting ones.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(feature = "latency")]
+use core::time::Duration;
+
+#[cfg(feature = "latency")]
+use crate::latency::{Histogram, LatencyHistogram};
+
+/// A snapshot of the counters of a [`Collector`](crate::Collector), see
+/// [`Collector::stats`](crate::Collector::stats).
+///
+/// The counters are read one after another while other threads keep retiring, so they are
+/// only consistent with each other once the collector is quiescent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CollectorStats {
+    /// Number of values retired into the collector.
+    pub retired: usize,
+    /// Number of retired values that were freed, or deferred functions that were run.
+    pub reclaimed: usize,
+    /// Number of batches published into the slots.
+    pub batches_published: usize,
+    /// Number of published batches that were freed.
+    pub batches_freed: usize,
+    /// Number of retired values still waiting in the batches of the threads.
+    pub pending: usize,
+    /// Number of threads pinned in each slot.
+    pub pinned: Vec<usize>,
+    /// Time between retiring values and freeing them. Requires the `latency` feature.
+    #[cfg(feature = "latency")]
+    pub latency: LatencyHistogram,
+}
+
+/// The number of values waiting in the batch of one thread or local handle. Only its owner
+/// writes to it, aligned so that the counters of different owners do not share a cache line.
+#[derive(Debug)]
+#[repr(align(64))]
+pub(crate) struct PendingCount {
+    count: AtomicUsize,
+    in_use: AtomicBool,
+    next: *mut PendingCount,
+}
+
+impl PendingCount {
+    pub(crate) fn set(&self, count: usize) {
+        self.count.store(count, Ordering::Relaxed);
+    }
+
+    /// Hands the counter back to the collector, for another thread or handle to use.
+    pub(crate) fn release(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// The counters of a collector which are updated once per batch, and the pending counters
+/// of its threads and local handles.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    batches_published: AtomicUsize,
+    nodes_published: AtomicUsize,
+    batches_freed: AtomicUsize,
+    reclaimed: AtomicUsize,
+    /// A list which only grows, the counters of the threads that are gone are reused.
+    pending: AtomicPtr<PendingCount>,
+    #[cfg(feature = "latency")]
+    latency: Histogram,
+}
+
+impl Stats {
+    pub(crate) const fn new() -> Self {
+        Stats {
+            batches_published: AtomicUsize::new(0),
+            nodes_published: AtomicUsize::new(0),
+            batches_freed: AtomicUsize::new(0),
+            reclaimed: AtomicUsize::new(0),
+            pending: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "latency")]
+            latency: Histogram::new(),
+        }
+    }
+
+    fn pending_counts(&self) -> impl Iterator<Item = &PendingCount> {
+        let mut next = self.pending.load(Ordering::Acquire);
+        core::iter::from_fn(move || {
+            let count = unsafe { next.as_ref()? };
+            next = count.next;
+            Some(count)
+        })
+    }
+
+    /// Returns a pending counter which is not used by anyone else until it is released. It
+    /// lives as long as the collector's stats.
+    pub(crate) fn acquire_pending(&self) -> NonNull<PendingCount> {
+        let free = self.pending_counts().find(|count| {
+            !count.in_use.load(Ordering::Relaxed)
+                && count
+                    .in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+        });
+        if let Some(count) = free {
+            return NonNull::from(count);
+        }
+        let count = Box::into_raw(Box::new(PendingCount {
+            count: AtomicUsize::new(0),
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.pending.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*count).next = head };
+            match self.pending.compare_exchange_weak(
+                head,
+                count,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return unsafe { NonNull::new_unchecked(count) },
+                Err(pres) => head = pres,
+            }
+        }
+    }
+
+    pub(crate) fn published(&self, nodes: usize) {
+        self.batches_published.fetch_add(1, Ordering::Relaxed);
+        self.nodes_published.fetch_add(nodes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn freed(&self, nodes: usize) {
+        self.batches_freed.fetch_add(1, Ordering::Relaxed);
+        self.reclaimed.fetch_add(nodes, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "latency")]
+    pub(crate) fn record_latency(&self, delay: Duration) {
+        self.latency.record(delay);
+    }
+
+    /// Combines the counters with the threads pinned per slot.
+    pub(crate) fn snapshot(&self, pinned: Vec<usize>) -> CollectorStats {
+        let pending = self
+            .pending_counts()
+            .map(|count| count.count.load(Ordering::Relaxed))
+            .sum();
+        CollectorStats {
+            retired: self.nodes_published.load(Ordering::Relaxed) + pending,
+            reclaimed: self.reclaimed.load(Ordering::Relaxed),
+            batches_published: self.batches_published.load(Ordering::Relaxed),
+            batches_freed: self.batches_freed.load(Ordering::Relaxed),
+            pending,
+            pinned,
+            #[cfg(feature = "latency")]
+            latency: self.latency.snapshot(),
+        }
+    }
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        let mut next = *self.pending.get_mut();
+        while let Some(count) = NonNull::new(next) {
+            let count = unsafe { Box::from_raw(count.as_ptr()) };
+            next = count.next;
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use crate::{CollectorBuilder, Smr};
+
+    #[test]
+    fn counts_retired_and_reclaimed() {
+        let collector = CollectorBuilder::new().slots(4).batch_size(8).build();
+        {
+            let guard = collector.pin();
+            for i in 0..20 {
+                collector.retire_box(Box::new(i), &guard);
+            }
+            let stats = collector.stats();
+            assert_eq!(stats.retired, 20);
+            assert_eq!(stats.pending, 4);
+            assert_eq!(stats.batches_published, 2);
+            assert_eq!(stats.reclaimed, 0);
+            assert_eq!(stats.pinned.iter().sum::<usize>(), 1);
+            assert_eq!(stats.pinned[guard.slot], 1);
+        }
+        let stats = collector.stats();
+        assert_eq!(stats.batches_freed, 2);
+        assert_eq!(stats.reclaimed, 16);
+        assert_eq!(stats.pinned, vec![0; 4]);
+
+        collector.flush();
+        let stats = collector.stats();
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.batches_published, 3);
+        assert_eq!(stats.reclaimed, 20);
+    }
+
+    #[test]
+    fn counts_all_threads() {
+        let collector = CollectorBuilder::new().slots(4).batch_size(4).build();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let guard = collector.pin();
+                    for i in 0..10 {
+                        let val = Box::new(i);
+                        guard.defer(move || drop(val));
+                    }
+                    drop(guard);
+                    collector.flush();
+                });
+            }
+        });
+        let stats = collector.stats();
+        assert_eq!(stats.retired, 40);
+        assert_eq!(stats.reclaimed, 40);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.batches_published, stats.batches_freed);
+        // The counters of the threads are reused.
+        let counters = collector.state().stats().pending_counts().count();
+        assert!(counters <= 4);
+        for _ in 0..4 {
+            thread::scope(|scope| {
+                scope
+                    .spawn(|| collector.retire_box(Box::new(0), &collector.pin()))
+                    .join()
+                    .unwrap();
+            });
+        }
+        assert_eq!(collector.state().stats().pending_counts().count(), counters);
+    }
+
+    #[test]
+    fn counts_local_handles() {
+        let collector = CollectorBuilder::new().slots(4).batch_size(8).build();
+        let handle = collector.register();
+        let guard = handle.pin();
+        for i in 0..3 {
+            collector.retire_box(Box::new(i), &guard);
+        }
+        let stats = collector.stats();
+        assert_eq!(stats.retired, 3);
+        assert_eq!(stats.pending, 3);
+        drop(guard);
+        drop(handle);
+        let stats = collector.stats();
+        assert_eq!(stats.retired, 3);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.reclaimed, 3);
+    }
+}
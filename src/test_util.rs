@@ -0,0 +1,39 @@
+//! Helpers shared by the unit tests.
+
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Counts how many of the values it handed out have been dropped.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DropCounter(Arc<AtomicUsize>);
+
+impl DropCounter {
+    pub(crate) fn new() -> Self {
+        DropCounter::default()
+    }
+
+    /// Returns a value which bumps the counter when it is dropped.
+    pub(crate) fn counted(&self) -> Counted {
+        Counted(self.0.clone())
+    }
+
+    /// Same as [`DropCounter::counted`], allocated to be retired.
+    pub(crate) fn boxed(&self) -> Option<NonNull<Counted>> {
+        NonNull::new(Box::into_raw(Box::new(self.counted())))
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value handed out by a [`DropCounter`].
+#[derive(Debug)]
+pub(crate) struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,375 @@
+//! Finds the guards that keep a collector from freeing its garbage, enabled by the `watchdog`
+//! feature.
+//!
+//! Every thread and every [`LocalHandle`](crate::LocalHandle) using the collector registers a
+//! record once, which it updates with atomics when it pins and unpins: the time it pinned
+//! and the number of nodes added to its slot until then. Nested guards share the record of
+//! the outer guard. The records are only locked to register them and to look for stalled
+//! guards when asked, publishing a batch never checks them.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+use crate::headnode::HeadNode;
+use crate::primitive::thread_local;
+
+type Hook = Arc<dyn Fn(&StalledGuard) + Send + Sync>;
+
+thread_local! {
+    static THREAD_RECORDS: RefCell<Vec<ThreadRecord>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The record of the current thread in one collector. The weak reference keeps the address
+/// of the records from being reused by another collector.
+struct ThreadRecord {
+    records: Weak<Mutex<State>>,
+    record: Arc<PinRecord>,
+}
+
+/// A guard which has been held for a long time, see
+/// [`Collector::long_lived_guards`](crate::Collector::long_lived_guards).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct StalledGuard {
+    /// The slot the guard is pinned in.
+    pub slot: usize,
+    /// The thread holding the guard. For a guard pinned through a
+    /// [`LocalHandle`](crate::LocalHandle), the thread which registered the handle.
+    pub thread: Thread,
+    /// How long the guard has been held.
+    pub held_for: Duration,
+    /// Number of nodes added to the slot since the guard was pinned. Every published batch
+    /// adds one node to each occupied slot, and none of them can be freed before the guard
+    /// is dropped.
+    pub queued: usize,
+}
+
+impl StalledGuard {
+    /// Returns the name of the thread holding the guard, if it has one.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread.name()
+    }
+}
+
+/// The pin of a thread or of a local handle, only written by its owner.
+#[derive(Debug)]
+pub(crate) struct PinRecord {
+    thread: Thread,
+    /// Nanoseconds since the watchdog was created plus one when the pin started, zero while
+    /// unpinned. It is reset before the other fields change, so that readers can tell a
+    /// consistent snapshot apart.
+    since: AtomicU64,
+    slot: AtomicUsize,
+    added: AtomicUsize,
+    reported: AtomicBool,
+}
+
+impl PinRecord {
+    fn new(thread: Thread) -> Self {
+        PinRecord {
+            thread,
+            since: AtomicU64::new(0),
+            slot: AtomicUsize::new(0),
+            added: AtomicUsize::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    fn start(&self, since: u64, slot: usize, head: &HeadNode) {
+        self.since.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.slot.store(slot, Ordering::Relaxed);
+        self.added.store(head.added(), Ordering::Relaxed);
+        self.reported.store(false, Ordering::Relaxed);
+        self.since.store(since, Ordering::Release);
+    }
+
+    /// Returns when the pin started and its slot, unless the record is not pinned or changed
+    /// while it was read.
+    fn snapshot(&self) -> Option<(u64, usize, usize)> {
+        let since = self.since.load(Ordering::Acquire);
+        if since == 0 {
+            return None;
+        }
+        let slot = self.slot.load(Ordering::Relaxed);
+        let added = self.added.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if self.since.load(Ordering::Relaxed) != since {
+            return None;
+        }
+        Some((since, slot, added))
+    }
+}
+
+struct State {
+    records: Vec<Arc<PinRecord>>,
+    hook: Option<(Duration, Hook)>,
+}
+
+/// The pins of a collector and the hook called for the stalled ones.
+pub(crate) struct Watchdog {
+    epoch: Instant,
+    has_hook: AtomicBool,
+    state: Arc<Mutex<State>>,
+}
+
+impl Watchdog {
+    pub(crate) fn new() -> Self {
+        Watchdog {
+            epoch: Instant::now(),
+            has_hook: AtomicBool::new(false),
+            state: Arc::new(Mutex::new(State {
+                records: Vec::new(),
+                hook: None,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is consistent even if a thread panicked while holding the lock.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a record for `thread`, for the current thread or for a local handle.
+    pub(crate) fn register(&self, thread: Thread) -> Arc<PinRecord> {
+        let record = Arc::new(PinRecord::new(thread));
+        let mut state = self.lock();
+        // Nobody can pin through the records only held here anymore.
+        state
+            .records
+            .retain(|record| Arc::strong_count(record) > 1 || record.snapshot().is_some());
+        state.records.push(record.clone());
+        record
+    }
+
+    /// Runs `f` with the record of the current thread, registering it on first use.
+    fn with_thread_record(&self, f: impl FnOnce(&PinRecord)) {
+        let key = Arc::as_ptr(&self.state);
+        // Not recorded while the thread is exiting.
+        let _ = THREAD_RECORDS.try_with(|records| {
+            let mut records = records.borrow_mut();
+            if let Some(record) = records.iter().find(|record| record.records.as_ptr() == key) {
+                return f(&record.record);
+            }
+            records.retain(|record| record.records.strong_count() > 0);
+            let record = self.register(thread::current());
+            f(&record);
+            records.push(ThreadRecord {
+                records: Arc::downgrade(&self.state),
+                record,
+            });
+        });
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64 + 1
+    }
+
+    /// Records a pin of `record`, or of the current thread if there is none.
+    pub(crate) fn pinned(&self, record: Option<&PinRecord>, slot: usize, head: &HeadNode) {
+        let since = self.now();
+        match record {
+            Some(record) => record.start(since, slot, head),
+            None => self.with_thread_record(|record| record.start(since, slot, head)),
+        }
+    }
+
+    pub(crate) fn unpinned(&self, record: Option<&PinRecord>) {
+        match record {
+            Some(record) => record.since.store(0, Ordering::Release),
+            None => self.with_thread_record(|record| record.since.store(0, Ordering::Release)),
+        }
+    }
+
+    /// Restarts the clock of a guard which was repinned without leaving its slot.
+    pub(crate) fn repinned(&self, record: Option<&PinRecord>, head: &HeadNode) {
+        let since = self.now();
+        let restart = |record: &PinRecord| {
+            let slot = record.slot.load(Ordering::Relaxed);
+            record.start(since, slot, head)
+        };
+        match record {
+            Some(record) => restart(record),
+            None => self.with_thread_record(restart),
+        }
+    }
+
+    fn stalled(
+        &self,
+        record: &PinRecord,
+        now: u64,
+        threshold: Duration,
+        slots: &[HeadNode],
+    ) -> Option<StalledGuard> {
+        let (since, slot, added) = record.snapshot()?;
+        let held_for = Duration::from_nanos(now.saturating_sub(since));
+        if held_for < threshold {
+            return None;
+        }
+        Some(StalledGuard {
+            slot,
+            thread: record.thread.clone(),
+            held_for,
+            queued: slots[slot].added().wrapping_sub(added),
+        })
+    }
+
+    pub(crate) fn long_lived(&self, threshold: Duration, slots: &[HeadNode]) -> Vec<StalledGuard> {
+        let now = self.now();
+        self.lock()
+            .records
+            .iter()
+            .filter_map(|record| self.stalled(record, now, threshold, slots))
+            .collect()
+    }
+
+    pub(crate) fn set_hook(&self, threshold: Duration, hook: Hook) {
+        let mut state = self.lock();
+        for record in &state.records {
+            record.reported.store(false, Ordering::Relaxed);
+        }
+        state.hook = Some((threshold, hook));
+        self.has_hook.store(true, Ordering::Release);
+    }
+
+    /// Calls the hook for the guards which became stalled since the last check.
+    pub(crate) fn check(&self, slots: &[HeadNode]) {
+        if !self.has_hook.load(Ordering::Acquire) {
+            return;
+        }
+        let now = self.now();
+        let (hook, stalled) = {
+            let state = self.lock();
+            let (threshold, hook) = match &state.hook {
+                Some((threshold, hook)) => (*threshold, hook.clone()),
+                None => return,
+            };
+            let stalled: Vec<_> = state
+                .records
+                .iter()
+                .filter_map(|record| {
+                    let stalled = self.stalled(record, now, threshold, slots)?;
+                    (!record.reported.swap(true, Ordering::Relaxed)).then_some(stalled)
+                })
+                .collect();
+            (hook, stalled)
+        };
+        // Called without the lock, the hook may use the collector.
+        for guard in &stalled {
+            hook(guard);
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("records", &self.lock().records)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{CollectorBuilder, Smr};
+
+    #[test]
+    fn long_lived_guards() {
+        let collector = CollectorBuilder::new().slots(2).batch_size(1).build();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let hook_reported = reported.clone();
+        collector.on_stalled_guard(Duration::from_millis(20), move |guard| {
+            hook_reported
+                .lock()
+                .unwrap()
+                .push((guard.thread_name().map(String::from), guard.queued));
+        });
+
+        let (pinned_tx, pinned_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let collector = &collector;
+        thread::scope(|scope| {
+            thread::Builder::new()
+                .name("stalled".into())
+                .spawn_scoped(scope, move || {
+                    let guard = collector.pin();
+                    let _nested = collector.pin();
+                    pinned_tx.send(guard.slot).unwrap();
+                    done_rx.recv().unwrap();
+                })
+                .unwrap();
+            let slot = pinned_rx.recv().unwrap();
+            assert!(collector
+                .long_lived_guards(Duration::from_secs(60))
+                .is_empty());
+
+            thread::sleep(Duration::from_millis(30));
+            {
+                let guard = collector.pin_in_slot(slot + 1);
+                guard.defer(|| {});
+            }
+            collector.flush();
+            collector.flush();
+            // Publishing a batch does not check the guards.
+            assert!(reported.lock().unwrap().is_empty());
+            collector.check_stalled_guards();
+            collector.check_stalled_guards();
+
+            let stalled = collector.long_lived_guards(Duration::from_millis(20));
+            assert_eq!(stalled.len(), 1);
+            assert_eq!(stalled[0].slot, slot);
+            assert_eq!(stalled[0].thread_name(), Some("stalled"));
+            assert_eq!(stalled[0].queued, 1);
+            assert!(stalled[0].held_for >= Duration::from_millis(30));
+            done_tx.send(()).unwrap();
+        });
+
+        assert!(collector.long_lived_guards(Duration::ZERO).is_empty());
+        // Reported once, by the first check.
+        assert_eq!(*reported.lock().unwrap(), vec![(Some("stalled".into()), 1)]);
+    }
+
+    #[test]
+    fn repin_restarts_clock() {
+        let collector = CollectorBuilder::new().slots(2).build();
+        let mut guard = collector.pin();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            collector.long_lived_guards(Duration::from_millis(20)).len(),
+            1
+        );
+        guard.repin();
+        assert!(collector
+            .long_lived_guards(Duration::from_millis(20))
+            .is_empty());
+
+        let handle = collector.register();
+        let _local = handle.pin();
+        assert_eq!(collector.long_lived_guards(Duration::ZERO).len(), 2);
+    }
+
+    #[test]
+    fn registers_once_per_thread() {
+        let collector = CollectorBuilder::new().slots(2).build();
+        for _i in 0..10 {
+            drop(collector.pin());
+        }
+        thread::scope(|scope| {
+            scope.spawn(|| drop(collector.pin())).join().unwrap();
+        });
+        // The record of the exited thread is dropped when the handle registers.
+        let _handle = collector.register();
+        assert_eq!(collector.state().watchdog().lock().records.len(), 2);
+    }
+}
@@ -1,8 +1,8 @@
 /*
-   These tests serve very little purpose for now as the atomicdouble type is a
-   custom atomic type and loom doesnt support it. These are here jst to test out loom's
-   features and maybe test some permutations using loom::thread.
-   Put it short -> This test is useless as of now
+   Run with RUSTFLAGS="--cfg loom" cargo test --test collector_test --release.
+   The collectors are created inside the models, a collector in a static outlives the model.
+   They have two slots and small batches, and the number of preemptions is bounded, to keep
+   the number of interleavings manageable.
 */
 #![cfg(loom)]
 use std::ptr;
@@ -16,64 +16,101 @@ use hyaline_smr::{
     self as hyaline, {Collector, Smr},
 };
 
-static DROP_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = Some(2);
+    }
+    builder.check(f);
+}
+
+fn small_collector() -> Collector {
+    Collector::builder().slots(2).batch_size(2).build()
+}
 
 struct TestNode {
-    foo: i32,
-    bar: i32,
+    drops: Arc<AtomicUsize>,
 }
+
 impl Drop for TestNode {
     fn drop(&mut self) {
-        DROP_COUNT.fetch_add(1, atomicdouble::Ordering::Release);
+        self.drops.fetch_add(1, Ordering::Release);
     }
 }
+
 #[test]
 fn collector_test() {
-    loom::model(|| {
-        let v1 = Arc::new(AtomicUsize::new(0));
-        let v2 = v1.clone();
-        let v3 = v1.clone();
+    model(|| {
+        let collector = Arc::new(small_collector());
+        let drops = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let collector = collector.clone();
+                let drops = drops.clone();
+                thread::spawn(move || {
+                    let guard = collector.pin();
+                    collector.retire_box(Box::new(TestNode { drops }), &guard);
+                    collector.flush();
+                })
+            })
+            .collect();
 
-        let loc_coll_1 = Arc::new(Collector::new());
-        let loc_coll_2 = loc_coll_1.clone();
-        let loc_coll_3 = loc_coll_1.clone();
+        {
+            let guard = collector.pin();
+            let node = Box::new(TestNode {
+                drops: drops.clone(),
+            });
+            collector.retire_box(node, &guard);
+            collector.flush();
+        }
 
-        let first_garb = Box::new(TestNode { foo: 1, bar: 2 });
-        let second_garb = Box::new(TestNode { foo: 2, bar: 2 });
-        let third_garb = Box::new(TestNode { foo: 3, bar: 2 });
-        let fourth_garb = Box::new(TestNode { foo: 4, bar: 2 });
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // Nobody is pinned anymore, so every retired value has been freed.
+        assert_eq!(drops.load(Ordering::Acquire), 3);
+    });
+}
 
-        let five_garb = Box::new(TestNode { foo: 5, bar: 2 });
-        let six_garb = Box::new(TestNode { foo: 6, bar: 2 });
+#[test]
+fn guard_protects_swapped_out_value() {
+    model(|| {
+        let collector = Arc::new(small_collector());
+        let drops = Arc::new(AtomicUsize::new(0));
+        let first = Box::into_raw(Box::new(TestNode {
+            drops: drops.clone(),
+        }));
+        let shared = Arc::new(AtomicPtr::new(first));
+
+        let reader = {
+            let collector = collector.clone();
+            let drops = drops.clone();
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let _guard = collector.pin();
+                let read = shared.load(Ordering::Acquire);
+                // The value may only be freed after the guard is dropped.
+                if read == first {
+                    assert_eq!(drops.load(Ordering::Acquire), 0);
+                }
+            })
+        };
 
+        let second = Box::into_raw(Box::new(TestNode {
+            drops: drops.clone(),
+        }));
         {
-            let guard1 = loc_coll_1.pin();
-            unsafe {
-                loc_coll_1.retire(NonNull::new(Box::into_raw(first_garb)), &guard1);
-                loc_coll_1.retire(NonNull::new(Box::into_raw(second_garb)), &guard1);
-            }
+            let guard = collector.pin();
+            let old = shared.swap(second, Ordering::AcqRel);
+            unsafe { collector.retire(NonNull::new(old), &guard) };
+            collector.flush();
         }
 
-        thread::spawn(move || {
-            let guard2 = loc_coll_2.pin();
-            unsafe {
-                loc_coll_2.retire(NonNull::new(Box::into_raw(third_garb)), &guard2);
-                loc_coll_2.retire(NonNull::new(Box::into_raw(fourth_garb)), &guard2);
-            }
-            v1.store(1, Ordering::Release);
-        });
-
-        thread::spawn(move || {
-            let guard3 = loc_coll_3.pin();
-            unsafe {
-                loc_coll_3.retire(NonNull::new(Box::into_raw(five_garb)), &guard3);
-                loc_coll_3.retire(NonNull::new(Box::into_raw(six_garb)), &guard3);
-            }
-            v2.store(2, Ordering::Release);
-        });
-        v3.load(Ordering::Acquire);
-        while DROP_COUNT.load(atomicdouble::Ordering::Acquire) < 6 {}
-        assert_eq!(DROP_COUNT.load(atomicdouble::Ordering::Acquire), 6);
+        reader.join().unwrap();
+        // Nobody is pinned anymore, so the swapped out value has been freed.
+        assert_eq!(drops.load(Ordering::Acquire), 1);
+        drop(unsafe { Box::from_raw(second) });
     });
 }
 
@@ -83,8 +120,9 @@ fn treiber_stack() {
     ///
     /// Usable with any number of producers and consumers.
     #[derive(Debug)]
-    pub struct TreiberStack<T> {
+    pub struct TreiberStack<T: Send + 'static> {
         head: AtomicPtr<Node<T>>,
+        collector: Collector,
     }
 
     #[derive(Debug)]
@@ -93,11 +131,12 @@ fn treiber_stack() {
         next: AtomicPtr<Node<T>>,
     }
 
-    impl<T> TreiberStack<T> {
+    impl<T: Send + 'static> TreiberStack<T> {
         /// Creates a new, empty stack.
         pub fn new() -> TreiberStack<T> {
             TreiberStack {
                 head: AtomicPtr::default(),
+                collector: small_collector(),
             }
         }
 
@@ -108,7 +147,7 @@ fn treiber_stack() {
                 next: AtomicPtr::default(),
             });
 
-            let _guard = hyaline::pin();
+            let _guard = self.collector.pin();
             let mut head = self.head.load(Ordering::Relaxed);
             let a_n = Box::into_raw(n);
             loop {
@@ -129,7 +168,7 @@ fn treiber_stack() {
         ///
         /// Returns `None` if the stack is empty.
         pub fn pop(&self) -> Option<T> {
-            let guard = hyaline_smr::pin();
+            let guard = self.collector.pin();
             loop {
                 let head = self.head.load(Ordering::Acquire);
 
@@ -143,7 +182,7 @@ fn treiber_stack() {
                             .is_ok()
                         {
                             unsafe {
-                                hyaline::retire(NonNull::new(head), &guard);
+                                self.collector.retire(NonNull::new(head), &guard);
                                 return Some(ManuallyDrop::into_inner(ptr::read(&(*h).data)));
                             }
                         }
@@ -155,30 +194,39 @@ fn treiber_stack() {
 
         /// Returns `true` if the stack is empty.
         pub fn is_empty(&self) -> bool {
-            let _guard = hyaline::pin();
+            let _guard = self.collector.pin();
             self.head.load(Ordering::Acquire).is_null()
         }
     }
 
-    impl<T> Drop for TreiberStack<T> {
+    impl<T: Send + 'static> Drop for TreiberStack<T> {
         fn drop(&mut self) {
-            while self.pop().is_some() {}
+            unsafe {
+                // Nobody else can reach the stack anymore.
+                let guard = hyaline::unprotected();
+                let mut head = self.head.load(Ordering::Relaxed);
+                while let Some(h) = head.as_mut() {
+                    let next = h.next.load(Ordering::Relaxed);
+                    ManuallyDrop::drop(&mut h.data);
+                    self.collector.retire(NonNull::new(head), guard);
+                    head = next;
+                }
+            }
         }
     }
 
-    loom::model(|| {
+    model(|| {
         let stack1 = Arc::new(TreiberStack::new());
         let stack2 = Arc::clone(&stack1);
 
-        // use 5 since it's greater than the 4 used for the sanitize feature
         let jh = thread::spawn(move || {
-            for i in 0..5 {
+            for i in 0..2 {
                 stack2.push(i);
                 assert!(stack2.pop().is_some());
             }
         });
 
-        for i in 0..5 {
+        for i in 0..2 {
             stack1.push(i);
             assert!(stack1.pop().is_some());
         }